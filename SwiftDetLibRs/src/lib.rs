@@ -389,20 +389,86 @@ fn soft_nms(mut boxes: Vec<BBox>) -> Vec<BBox> {
 
 
 
-// 执行检测
-pub fn detect<F: FnMut(&usize, &usize)>(image_path: &str, config: DetectConfig, env: Environment, mut progress_callback: F, do_nms: bool) -> Result<Vec<BBox>, String>
+// 拼接后的Heatmap 用于调试
+// 分辨率为原图的 heatmap_size / input_size 倍
+pub struct StitchedHeatmap {
+    pub width: usize,
+    pub height: usize,
+    // 按行存储 经过sigmoid 取值范围0~1
+    pub data: Vec<f32>,
+}
+
+impl StitchedHeatmap {
+    fn new(width: usize, height: usize) -> Self {
+        StitchedHeatmap {
+            width,
+            height,
+            data: vec![0.; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    // 将一个区块的Heatmap贴到对应位置 重叠部分取最大值
+    fn paste(&mut self, hm: &ArrayView2<f32>, metadata: &Metadata, config: &DetectConfig, stride: (f32, f32)) {
+        let width_scale = config.input_size.0 as f32 / config.heatmap_size.0 as f32;
+        let height_scale = config.input_size.1 as f32 / config.heatmap_size.1 as f32;
+        // 区块在原图坐标系中的范围 与apply_metadata保持一致
+        let start_x = metadata.start_x as f32;
+        let start_y = metadata.start_y as f32;
+        let end_x = start_x + config.input_size.0 as f32 * metadata.width_scale;
+        let end_y = start_y + config.input_size.1 as f32 * metadata.height_scale;
+        let from_x = (start_x / stride.0) as usize;
+        let to_x = core::cmp::min((end_x / stride.0).ceil() as usize, self.width);
+        let from_y = (start_y / stride.1) as usize;
+        let to_y = core::cmp::min((end_y / stride.1).ceil() as usize, self.height);
+        // 反向映射 避免放大时出现空隙
+        for cy in from_y..to_y {
+            for cx in from_x..to_x {
+                let hx = ((cx as f32 * stride.0 - start_x) / metadata.width_scale / width_scale) as usize;
+                let hy = ((cy as f32 * stride.1 - start_y) / metadata.height_scale / height_scale) as usize;
+                if hx >= config.heatmap_size.0 || hy >= config.heatmap_size.1 {
+                    continue;
+                }
+                let value = sigmoid(hm[[hx, hy]]);
+                let cell = &mut self.data[cy * self.width + cx];
+                if value > *cell {
+                    *cell = value;
+                }
+            }
+        }
+    }
+}
+
+// 在已有的会话上检测一张图像
+fn detect_image<F: FnMut(&usize, &usize)>(image_path: &str, config: &DetectConfig, sess: &mut Session, mut progress_callback: F, do_nms: bool, keep_heatmap: bool) -> Result<(Vec<BBox>, Option<StitchedHeatmap>), String>
 {
     match image::open(image_path) {
         Ok(image) => {
             let image = image.into_rgb8();
+            let (origin_width, origin_height) = image.dimensions();
             let image = image.into_ndarray3();
 
-            let array_image = preprocess(&image, &config);
-            let batches = split_tiles(array_image, &config);
-            let mut sess = make_session(&env, &config);
+            let array_image = preprocess(&image, config);
+            let batches = split_tiles(array_image, config);
             let mut current = 1;
             let total = batches.len();
             let mut all_boxes = Vec::new();
+            // Heatmap上一个点对应原图的像素数
+            let stride = (
+                config.input_size.0 as f32 / config.heatmap_size.0 as f32,
+                config.input_size.1 as f32 / config.heatmap_size.1 as f32,
+            );
+            let mut stitched = if keep_heatmap {
+                Some(StitchedHeatmap::new(
+                    (origin_width as f32 / stride.0).ceil() as usize,
+                    (origin_height as f32 / stride.1).ceil() as usize,
+                ))
+            } else {
+                None
+            };
 
             for (batch, metadata) in batches {
                 // 调用Python的回调函数
@@ -416,15 +482,18 @@ pub fn detect<F: FnMut(&usize, &usize)>(image_path: &str, config: DetectConfig,
                     .unwrap();
                 let wh = wh.into_shape((config.heatmap_size.1, config.heatmap_size.0, 2 as usize))
                     .unwrap();
-                let tile_boxes = apply_metadata(decode_heatmap(&hm, &wh, &config), &metadata);
+                if let Some(stitched) = stitched.as_mut() {
+                    stitched.paste(&hm, &metadata, config, stride);
+                }
+                let tile_boxes = apply_metadata(decode_heatmap(&hm, &wh, config), &metadata);
 
                 all_boxes.extend(tile_boxes);
                 current += 1;
             }
             if do_nms {
-                Ok(soft_nms(all_boxes))
+                Ok((soft_nms(all_boxes), stitched))
             } else {
-                Ok(all_boxes)
+                Ok((all_boxes, stitched))
             }
         }
         Err(e) => {
@@ -432,3 +501,38 @@ pub fn detect<F: FnMut(&usize, &usize)>(image_path: &str, config: DetectConfig,
         }
     }
 }
+
+fn run_detect<F: FnMut(&usize, &usize)>(image_path: &str, config: DetectConfig, env: Environment, progress_callback: F, do_nms: bool, keep_heatmap: bool) -> Result<(Vec<BBox>, Option<StitchedHeatmap>), String>
+{
+    let mut sess = make_session(&env, &config);
+    detect_image(image_path, &config, &mut sess, progress_callback, do_nms, keep_heatmap)
+}
+
+// 执行检测
+pub fn detect<F: FnMut(&usize, &usize)>(image_path: &str, config: DetectConfig, env: Environment, progress_callback: F, do_nms: bool) -> Result<Vec<BBox>, String>
+{
+    run_detect(image_path, config, env, progress_callback, do_nms, false)
+        .map(|(boxes, _)| boxes)
+}
+
+// 执行检测 并额外返回拼接后的Heatmap 用于调试模型
+pub fn detect_with_heatmap<F: FnMut(&usize, &usize)>(image_path: &str, config: DetectConfig, env: Environment, progress_callback: F, do_nms: bool) -> Result<(Vec<BBox>, StitchedHeatmap), String>
+{
+    run_detect(image_path, config, env, progress_callback, do_nms, true)
+        .map(|(boxes, stitched)| (boxes, stitched.unwrap()))
+}
+
+// 依次检测多张图像 只加载一次模型
+// 每张图像检测完毕后调用callback 参数为图像序号和检测结果
+// callback返回false时停止检测剩余的图像
+pub fn detect_many<F: FnMut(usize, Result<Vec<BBox>, String>) -> bool>(image_paths: &[String], config: DetectConfig, env: Environment, mut callback: F, do_nms: bool)
+{
+    let mut sess = make_session(&env, &config);
+    for (index, image_path) in image_paths.iter().enumerate() {
+        let result = detect_image(image_path, &config, &mut sess, |_, _| {}, do_nms, false)
+            .map(|(boxes, _)| boxes);
+        if !callback(index, result) {
+            break;
+        }
+    }
+}
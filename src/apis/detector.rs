@@ -7,12 +7,19 @@
 use log::{info, warn};
 use tide::{Request, Response, Server};
 use tide::prelude::*;
-use wither::bson::doc;
-use swift_det_lib::{BBox, detect, DetectConfig, make_env};
-use crate::apis::{json_response, require_perm};
+use wither::bson::{Bson, doc, Document};
+use swift_det_lib::{BBox, detect, detect_with_heatmap, DetectConfig, make_env, StitchedHeatmap};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use crate::apis::{has_capability, json_response, require_capability};
+use crate::apis::storage::random_filename;
 use crate::AppState;
+use crate::roles;
+use crate::config::StorageConfig;
 use crate::models::detections::Detection;
 use crate::models::Session;
+use crate::models::storage::Storage;
 use wither::Model;
 use crate::models::SearchById;
 use futures::StreamExt;
@@ -27,8 +34,12 @@ pub fn register(app: &mut Server<AppState>) {
         .put(api_update_task)
         .delete(api_delete_task);
     app.at("/detector/:task_id/draw").get(api_draw);
+    app.at("/detector/:task_id/heatmap").get(api_heatmap);
+    app.at("/detector/:task_id/rerun").post(api_rerun_task);
+    app.at("/detector/:task_id/runs").get(api_get_task_runs);
     app.at("/detector/:task_id/count").get(api_compute_number);
     app.at("/detector/mine").get(api_get_user_detections);
+    app.at("/detector/stats").get(api_get_stats);
 }
 
 #[derive(Deserialize)]
@@ -38,15 +49,80 @@ struct CreateTaskForm {
     overlap: u8,
     window_size: usize,
     tile_max_num: u16,
+    // 调试模式 额外保存Heatmap
+    debug: Option<bool>,
 }
 
-async fn do_task(db: Database, task_id: ObjectId, task_config: DetectConfig) {
+// 将拼接后的Heatmap保存为灰度图 并登记到Storage中
+async fn save_heatmap(db: &Database, storage_config: &StorageConfig, task: &Detection, heatmap: StitchedHeatmap) -> Option<String> {
+    let task_id = task.id.as_ref().unwrap().to_hex();
+    let img = image::GrayImage::from_fn(heatmap.width as u32, heatmap.height as u32, |x, y| {
+        image::Luma([(heatmap.get(x as usize, y as usize) * 255.) as u8])
+    });
+    let local_path = storage_config.get_path(random_filename("png".to_string()));
+    if let Err(e) = img.save(&local_path) {
+        warn!("任务 {} 的Heatmap保存失败: {}", &task_id, e);
+        return None;
+    }
+    let mut storage = Storage {
+        id: None,
+        filename: format!("heatmap-{}.png", &task_id),
+        local_path,
+        mime_type: "image/png".to_string(),
+        created_at: chrono::Utc::now().into(),
+        owner: task.creator.clone(),
+    };
+    if let Err(e) = storage.save(db, None).await {
+        warn!("任务 {} 的Heatmap登记失败: {}", &task_id, e);
+        return None;
+    }
+    Some(storage.id.unwrap().to_hex())
+}
+
+// 模型文件的md5 按路径缓存 文件被修改后重新计算
+async fn model_checksum(path: &str) -> std::io::Result<String> {
+    static CHECKSUMS: OnceLock<Mutex<HashMap<String, (SystemTime, String)>>> = OnceLock::new();
+    let checksums = CHECKSUMS.get_or_init(|| Mutex::new(HashMap::new()));
+    let modified = async_std::fs::metadata(path).await?.modified()?;
+    if let Some((cached_at, checksum)) = checksums.lock().unwrap().get(path) {
+        if *cached_at == modified {
+            return Ok(checksum.clone());
+        }
+    }
+    let checksum = format!("{:x}", md5::compute(async_std::fs::read(path).await?));
+    checksums.lock().unwrap().insert(path.to_owned(), (modified, checksum.clone()));
+    Ok(checksum)
+}
+
+async fn do_task(db: Database, storage_config: StorageConfig, task_id: ObjectId, task_config: DetectConfig) {
     let env = make_env();
     let env = env.unwrap();
     info!("开始检测任务");
     let mut task = Detection::by_id(&db, &task_id.to_hex()).await.unwrap();
     let attachment = task.get_attachment(&db).await.unwrap();
-    let result = detect(attachment.local_path.as_str(), task_config, env, |current, total| {
+    // 记录开始时间 用于统计处理耗时
+    let started_at = chrono::Utc::now();
+    task.started_at = Some(started_at.into());
+    // 记录模型文件的校验和 同名模型被替换后仍可追溯
+    task.model_checksum = match model_checksum(&task_config.model_path).await {
+        Ok(checksum) => Some(checksum),
+        Err(e) => {
+            warn!("任务 {} 无法读取模型文件: {}", &task_id, e);
+            None
+        }
+    };
+    let mut started = doc! {"started_at": started_at};
+    if let Some(checksum) = &task.model_checksum {
+        started.insert("model_checksum", checksum);
+    }
+    if let Err(e) = task.to_owned().update(&db, None, doc! {
+        "$set": started
+    }, None).await {
+        warn!("任务 {} 无法记录开始时间: {}", &task_id, e);
+    }
+    let mut tiles = 0;
+    let progress = |current: &usize, total: &usize| {
+        tiles = *total;
         let task = task.to_owned();
         if let Ok(..) = async_std::task::block_on(
             task.update(&db, None, doc! {
@@ -61,27 +137,44 @@ async fn do_task(db: Database, task_id: ObjectId, task_config: DetectConfig) {
         } else {
             warn!("任务 {} 更新失败 进度 {}/{}", &task_id, current, total);
         }
-    }, false);
-    if result.is_err() {
-        if let Ok(..) = task.update(&db, None, doc! {
-                "$set": {
-                    "status": "failed",
-                },
-                "$unset": {
-                    "result": "",
-                    "current": "",
-                    "total": "",
-                }
-            }, None).await {
-            warn!("任务 {} 失败", &task_id);
-        } else {
-            warn!("任务 {} 失败 + 更新失败", &task_id);
+    };
+    let result = if task.debug.unwrap_or(false) {
+        detect_with_heatmap(attachment.local_path.as_str(), task_config, env, progress, false)
+            .map(|(boxes, heatmap)| (boxes, Some(heatmap)))
+    } else {
+        detect(attachment.local_path.as_str(), task_config, env, progress, false)
+            .map(|boxes| (boxes, None))
+    };
+    let (boxes, heatmap) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            if let Ok(..) = task.update(&db, None, doc! {
+                    "$set": {
+                        "status": "failed",
+                        "finished_at": chrono::Utc::now(),
+                        "error": e,
+                    },
+                    "$unset": {
+                        "result": "",
+                        "current": "",
+                        "total": "",
+                    }
+                }, None).await {
+                warn!("任务 {} 失败", &task_id);
+            } else {
+                warn!("任务 {} 失败 + 更新失败", &task_id);
+            }
+            return;
         }
-        return;
-    }
+    };
     info!("任务 {} 执行完毕", &task_id);
+    if let Some(heatmap) = heatmap {
+        task.heatmap = save_heatmap(&db, &storage_config, &task, heatmap).await;
+    }
     task.status = "finished".to_owned();
-    task.result = Some(result.unwrap());
+    task.result = Some(boxes);
+    task.total = Some(tiles as isize);
+    task.finished_at = Some(chrono::Utc::now().into());
     task.save(&db, None).await.unwrap();
 }
 
@@ -119,48 +212,166 @@ async fn api_create_task(mut req: Request<AppState>) -> tide::Result<tide::Respo
         window_size: form.window_size as isize,
         overlap: form.overlap as i8,
         tile_max_num: form.tile_max_num as i16,
+        model_version: state.config.ai.get_model(&form.model_name).unwrap().version.clone(),
         model_name: form.model_name,
         result: None,
         current: None,
         total: None,
         threshold: None,
+        debug: form.debug,
+        heatmap: None,
+        started_at: None,
+        finished_at: None,
+        error: None,
+        model_checksum: None,
+        parent: None,
     };
-
+    if task.get_attachment(&state.db).await.is_none() {
+        return Ok(json_response(400, json!({
+            "code": 4,
+            "message": {
+                "cn": "附件不存在",
+                "en": "Attachment not found",
+            },
+        })));
+    }
 
     // 将任务插入数据库
     task.save(&state.db, None).await?;
+    spawn_task(state, &task, model_path);
+    Ok(json!({
+        "task_id": task.id.unwrap().to_hex()
+    }).into())
+}
+
+// 在新线程中执行一个已经保存的任务
+fn spawn_task(state: &AppState, task: &Detection, model_path: String) {
     let task_id = task.id.as_ref().unwrap().to_owned();
     let task_config = task.get_config(model_path);
-    let attachment = task.get_attachment(&state.db).await;
-    if attachment.is_none() {
-        let mut resp = tide::Response::new(tide::StatusCode::BadRequest);
-        resp.set_body(json!({
-                "code": 4,
-                "message": {
-                    "cn": "附件不存在",
-                    "en": "Attachment not found",
-                },
-                "description": {
-                    "task_id": task_id,
-                },
-            }));
-        return Ok(resp);
-    }
-
-    // 启动任务
     // 这两个数据是要送给closure的
     let db = state.db.clone();
+    let storage_config = state.config.storage.clone();
     std::thread::Builder::new()
         .stack_size(2 * 1024 * 1024 * 1024)
         .spawn(move || {
-            async_std::task::block_on(do_task(db, task_id, task_config));
+            async_std::task::block_on(do_task(db, storage_config, task_id, task_config));
         })
         .unwrap();
+}
+
+#[derive(Deserialize)]
+struct RerunTaskForm {
+    // 不指定时使用原任务的模型
+    model_name: Option<String>,
+}
+
+// 使用相同参数重新执行一个任务 原结果保留 便于对比
+async fn api_rerun_task(mut req: Request<AppState>) -> tide::Result<Response> {
+    require_capability(&req, roles::DETECTOR_USE).await?;
+    let form: RerunTaskForm = req.body_json().await.unwrap_or(RerunTaskForm { model_name: None });
+    let task_id = req.param("task_id").unwrap().to_owned();
+    let session: &Session = req.ext().unwrap();
+    let state = req.state();
+    let origin = Detection::by_id(&state.db, &task_id).await;
+    if origin.is_none() {
+        return Ok(json_response(404, json!({
+            "code": 4,
+            "message": {
+                "cn": "任务不存在",
+                "en": "Task not found",
+            },
+        })));
+    }
+    let origin = origin.unwrap();
+    // 只能重新执行自己的任务
+    if !has_capability(state, session, roles::SYSTEM_ADMIN)
+        && session.user.as_ref() != Some(&origin.creator) {
+        return Ok(json_response(403, json!({
+            "code": 1,
+            "message": {
+                "cn": "您没有权限重新执行该任务",
+                "en": "You have no permission to rerun this task",
+            },
+        })));
+    }
+    let model_name = form.model_name.unwrap_or(origin.model_name.clone());
+    let model = state.config.ai.get_model(&model_name);
+    if model.is_none() {
+        return Ok(json_response(400, json!({
+            "code": 4,
+            "message": {
+                "cn": "模型不存在",
+                "en": "Model not found",
+            },
+            "description": {
+                "model_name": model_name,
+            },
+        })));
+    }
+    let model = model.unwrap();
+    let mut task = Detection {
+        id: None,
+        creator: session.user.to_owned().unwrap(),
+        created_at: chrono::Utc::now().into(),
+        status: "pending".to_string(),
+        attachment: origin.attachment.clone(),
+        window_size: origin.window_size,
+        overlap: origin.overlap,
+        tile_max_num: origin.tile_max_num,
+        model_name,
+        result: None,
+        current: None,
+        total: None,
+        threshold: origin.threshold,
+        debug: origin.debug,
+        heatmap: None,
+        started_at: None,
+        finished_at: None,
+        error: None,
+        model_version: model.version.clone(),
+        model_checksum: None,
+        // 所有重新执行的任务都指向最初的任务
+        parent: Some(origin.parent.clone().unwrap_or(task_id)),
+    };
+    task.save(&state.db, None).await?;
+    spawn_task(state, &task, model.path.clone());
     Ok(json!({
         "task_id": task.id.unwrap().to_hex()
     }).into())
 }
 
+// 列出一个任务及其所有重新执行的结果
+async fn api_get_task_runs(req: Request<AppState>) -> tide::Result {
+    let task_id = req.param("task_id").unwrap().to_owned();
+    let state = req.state();
+    let db = &state.db.to_owned();
+    let query = req.query::<DrawQuery>().unwrap_or(DrawQuery { threshold: 0.5 });
+    let task = Detection::by_id(db, &task_id).await;
+    if task.is_none() {
+        return Ok(json_response(404, json!({
+            "code": 4,
+            "message": {
+                "cn": "任务不存在",
+                "en": "Task not found",
+            },
+        })));
+    }
+    let root = task.unwrap().parent.unwrap_or(task_id);
+    let root_oid = ObjectId::with_string(&root)?;
+    let runs: Vec<_> = Detection::find(db, Some(doc! {
+        "$or": [{"_id": root_oid}, {"parent": &root}]
+    }), None).await?.collect().await;
+    let mut result = Vec::new();
+    for run in runs.into_iter().flatten() {
+        let count = run.count(query.threshold);
+        result.push(json!({
+            "info": run.to_info().await,
+            "count": count,
+        }));
+    }
+    Ok(json!(result).into())
+}
+
 async fn api_get_task_status(req: Request<AppState>) -> tide::Result<tide::Response> {
     let task_id = req.param("task_id").unwrap();
     let state = req.state();
@@ -316,6 +527,75 @@ async fn api_draw(req: Request<AppState>) -> tide::Result<Response> {
     }
 }
 
+// 将灰度值映射为jet色图
+fn jet_color(value: u8) -> image::Rgb<u8> {
+    let v = value as f32 / 255.;
+    let r = (1.5 - (4. * v - 3.).abs()).clamp(0., 1.);
+    let g = (1.5 - (4. * v - 2.).abs()).clamp(0., 1.);
+    let b = (1.5 - (4. * v - 1.).abs()).clamp(0., 1.);
+    image::Rgb([(r * 255.) as u8, (g * 255.) as u8, (b * 255.) as u8])
+}
+
+#[derive(Deserialize)]
+struct HeatmapQuery {
+    colormap: Option<bool>,
+}
+
+async fn api_heatmap(req: Request<AppState>) -> tide::Result<Response> {
+    let task_id = req.param("task_id").unwrap();
+    let state = req.state();
+    let query = req.query::<HeatmapQuery>().unwrap_or(HeatmapQuery { colormap: None });
+    if let Some(task) = Detection::by_id(&state.db, &task_id.to_string()).await {
+        if let Some(heatmap) = task.get_heatmap(&state.db).await {
+            if let Ok(img) = image::open(&heatmap.local_path) {
+                let img = img.to_luma8();
+                let buffer = Vec::new();
+                let mut buffer = std::io::Cursor::new(buffer);
+                if query.colormap.unwrap_or(false) {
+                    let colored = image::RgbImage::from_fn(img.width(), img.height(), |x, y| {
+                        jet_color(img.get_pixel(x, y)[0])
+                    });
+                    colored.write_to(&mut buffer, image::ImageOutputFormat::Png).unwrap();
+                } else {
+                    img.write_to(&mut buffer, image::ImageOutputFormat::Png).unwrap();
+                }
+                let mut resp = tide::Response::new(tide::StatusCode::Ok);
+                resp.set_body(buffer.into_inner());
+                resp.set_content_type("image/png");
+                resp.insert_header("Cache-Control", "max-age=114514");
+                Ok(resp)
+            } else {
+                Ok(json_response(500, json!({
+                    "code": 500,
+                    "message": {
+                        "cn": "无法读取Heatmap文件",
+                        "en": "Can't read heatmap file",
+                    },
+                })))
+            }
+        } else {
+            Ok(json_response(404, json!({
+                "code": 1001,
+                "message": {
+                    "cn": "该任务没有Heatmap 请在调试模式下创建任务",
+                    "en": "Task has no heatmap, create it in debug mode",
+                },
+                "description": {
+                    "task_id": task_id,
+                },
+            })))
+        }
+    } else {
+        Ok(json_response(404, json!({
+            "code": 4,
+            "message": {
+                "cn": "任务不存在",
+                "en": "Task not found",
+            },
+        })))
+    }
+}
+
 #[derive(Deserialize)]
 struct UpdateTaskForm {
     threshold: f64,
@@ -323,14 +603,14 @@ struct UpdateTaskForm {
 
 
 async fn api_update_task(mut req: Request<AppState>) -> tide::Result<tide::Response> {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::DETECTOR_USE).await?;
     let form: UpdateTaskForm = req.body_json().await?;
     let task_id = req.param("task_id").unwrap().to_owned();
     let state = req.state();
     let db = &state.db.to_owned();
     if let Some(mut task) = Detection::by_id(db, &task_id).await {
         task.threshold = Some(form.threshold);
-        task.save(&db, None).await?;
+        task.save(db, None).await?;
         Ok(json!(task.to_info().await).into())
     } else {
         Ok(json_response(404, json!( {
@@ -344,12 +624,13 @@ async fn api_update_task(mut req: Request<AppState>) -> tide::Result<tide::Respo
 }
 
 async fn api_delete_task(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::DETECTOR_USE).await?;
     let task_id = req.param("task_id").unwrap().to_owned();
     let state = req.state();
     let db = &state.db.to_owned();
     if let Some(task) = Detection::by_id(db, &task_id).await {
-        task.delete(&db).await?;
+        task.remove_heatmap(db).await?;
+        task.delete(db).await?;
         Ok(Response::new(204))
     } else {
         Ok(json_response(404, json!( {
@@ -399,19 +680,105 @@ async fn api_compute_number(req: Request<AppState>) -> tide::Result {
 
 
 async fn api_get_user_detections(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::DETECTOR_USE).await?;
     let state = req.state();
     let db = &state.db.to_owned();
     let session: &Session = req.ext().unwrap();
     // 查询用户的任务
     let mut result = Vec::new();
-    let detections: Vec<_> = Detection::find(&db, Some(doc! {
+    let detections: Vec<_> = Detection::find(db, Some(doc! {
         "creator": session.user.as_ref().unwrap()
     }), None).await?.collect().await;
-    for detection in detections {
-        if let Ok(detection) = detection {
-            result.push(detection.to_info().await);
-        }
+    for detection in detections.into_iter().flatten() {
+        result.push(detection.to_info().await);
     }
     Ok(json!(result).into())
-}
\ No newline at end of file
+}
+#[derive(Deserialize)]
+struct StatsQuery {
+    // 统计最近多少天的任务
+    days: Option<i64>,
+}
+
+async fn aggregate_detections(db: &Database, pipeline: Vec<Document>) -> tide::Result<Vec<serde_json::Value>> {
+    let docs: Vec<_> = db.collection("detections")
+        .aggregate(pipeline, None)
+        .await?
+        .collect()
+        .await;
+    let mut result = Vec::new();
+    for doc in docs {
+        result.push(Bson::Document(doc?).into_relaxed_extjson());
+    }
+    Ok(result)
+}
+
+// 检测器使用情况统计 仅管理员可用
+async fn api_get_stats(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::SYSTEM_ADMIN).await?;
+    let state = req.state();
+    let db = &state.db.to_owned();
+    let query = req.query::<StatsQuery>().unwrap_or(StatsQuery { days: None });
+    let since = chrono::Utc::now() - chrono::Duration::days(query.days.unwrap_or(30));
+    let matched = doc! {"$match": {"created_at": {"$gte": since}}};
+
+    let per_day = aggregate_detections(db, vec![
+        matched.clone(),
+        doc! {"$group": {
+            "_id": {"$dateToString": {"format": "%Y-%m-%d", "date": "$created_at"}},
+            "count": {"$sum": 1},
+        }},
+        doc! {"$sort": {"_id": 1}},
+    ]).await?;
+    let status = aggregate_detections(db, vec![
+        matched.clone(),
+        doc! {"$group": {"_id": "$status", "count": {"$sum": 1}}},
+    ]).await?;
+    // 只统计记录了起止时间的任务 单位为毫秒
+    let models = aggregate_detections(db, vec![
+        matched.clone(),
+        doc! {"$match": {
+            "status": "finished",
+            "started_at": {"$exists": true},
+            "finished_at": {"$exists": true},
+        }},
+        doc! {"$group": {
+            "_id": "$model_name",
+            "count": {"$sum": 1},
+            "mean_processing_ms": {"$avg": {"$subtract": ["$finished_at", "$started_at"]}},
+            "mean_tiles": {"$avg": "$total"},
+        }},
+        doc! {"$sort": {"count": -1}},
+    ]).await?;
+    let tiles = aggregate_detections(db, vec![
+        matched.clone(),
+        doc! {"$match": {"status": "finished", "total": {"$exists": true}}},
+        doc! {"$group": {"_id": Bson::Null, "mean": {"$avg": "$total"}}},
+    ]).await?;
+    let failures = aggregate_detections(db, vec![
+        matched.clone(),
+        doc! {"$match": {"status": "failed"}},
+        doc! {"$group": {
+            "_id": {"$ifNull": ["$error", "unknown"]},
+            "count": {"$sum": 1},
+        }},
+        doc! {"$sort": {"count": -1}},
+        doc! {"$limit": 20},
+    ]).await?;
+    let creators = aggregate_detections(db, vec![
+        matched,
+        doc! {"$group": {"_id": "$creator", "count": {"$sum": 1}}},
+        doc! {"$sort": {"count": -1}},
+        doc! {"$limit": 10},
+    ]).await?;
+
+    Ok(json!({
+        "since": since.timestamp(),
+        "per_day": per_day,
+        "status": status,
+        "models": models,
+        "mean_tiles": tiles.first().and_then(|t| t.get("mean").cloned()),
+        "failures": failures,
+        "top_creators": creators,
+    }).into())
+}
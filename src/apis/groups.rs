@@ -1,9 +1,9 @@
 //
 use serde_json::json;
 use tide::{Request, Server};
-use wither::bson::doc;
-use crate::apis::{json_response, require_perm};
+use crate::apis::{is_group_manager, json_response, require_capability, require_group_manager};
 use crate::AppState;
+use crate::roles;
 use crate::forms::groups::{CreateGroupForm, JoinInvitationForm, UpdateGroupForm};
 use crate::models::groups::Group;
 use crate::models::{SearchById, Session};
@@ -12,6 +12,7 @@ use wither::Model;
 use futures::StreamExt;
 use crate::apis::users::random_string;
 use crate::models::invitations::Invitation;
+use crate::models::memberships::{self, Membership};
 
 pub fn register(app: &mut Server<AppState>) {
     // 未来实现
@@ -41,23 +42,15 @@ pub fn register(app: &mut Server<AppState>) {
 
 async fn api_get_manageable_groups(req: Request<AppState>) -> tide::Result {
     // 获取可管理的群组
-    require_perm(&req, vec![2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let session: &Session = req.ext().unwrap();
-    // 获取group.managers含有session.user的group
-    let groups: Vec<_> = Group::find(&db, Some(doc! {
-            "managers":{
-                "$elemMatch": {"$eq": session.user.as_ref().unwrap()}
-            }
-        }
-    ), None)
-        .await?
-        .collect()
-        .await;
+    // 获取用户担任管理员的group
+    let group_ids = Membership::managed_groups(&db, session.user.as_ref().unwrap()).await?;
     let mut result = Vec::new();
-    for group in groups {
-        if let Ok(group) = group {
+    for group_id in group_ids {
+        if let Some(group) = Group::by_id(&db, &group_id).await {
             result.push(group.to_response());
         }
     }
@@ -65,7 +58,7 @@ async fn api_get_manageable_groups(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_create_group(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![2, 3]).await?;
+    require_capability(&req, roles::GROUPS_MANAGE).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let session: &Session = req.ext().unwrap();
@@ -76,18 +69,14 @@ async fn api_create_group(mut req: Request<AppState>) -> tide::Result {
         id: None,
         name: form.name,
         created_at: chrono::Utc::now().into(),
-        managers: vec![session.user.to_owned().unwrap()],
+        managers: vec![],
         cover: None,
     };
     group.save(&db, None).await?;
-    let mut user = User::by_id(&db, &session.user.unwrap()).await.unwrap();
-    // 将用户加入群组
-    if let Some(ref mut groups) = user.groups {
-        groups.push(group.id.as_ref().unwrap().to_hex());
-    } else {
-        user.groups = Some(vec![group.id.as_ref().unwrap().to_hex()]);
-    }
-    user.save(&db, None).await?;
+    // 创建者成为小组的管理员
+    let group_id = group.id.as_ref().unwrap().to_hex();
+    Membership::join(&db, &session.user.unwrap(), &group_id, memberships::MANAGER).await?;
+    let group = Group::by_id(&db, &group_id).await.unwrap();
     Ok(json!(group).into())
 }
 
@@ -112,7 +101,7 @@ async fn api_get_group(req: Request<AppState>) -> tide::Result {
 
 async fn api_get_group_members(req: Request<AppState>) -> tide::Result {
     // 获取所有groups包含group_id的user
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let group_id = req.param("group_id").unwrap().to_owned();
@@ -126,7 +115,7 @@ async fn api_get_group_members(req: Request<AppState>) -> tide::Result {
 
 async fn api_get_groups(req: Request<AppState>) -> tide::Result {
     // 获取所有groups
-    // require_perm(&req, vec![1, 2, 3]).await?;
+    // require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let groups: Vec<_> = Group::find(&db, None, None)
@@ -144,10 +133,10 @@ async fn api_get_groups(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_update_group(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![2, 3]).await?;
+    let group_id = req.param("group_id").unwrap().to_owned();
+    require_group_manager(&req, &group_id).await?;
     let state = req.state();
     let db = state.db.to_owned();
-    let group_id = req.param("group_id").unwrap().to_owned();
     let form: UpdateGroupForm = req.body_json().await?;
     form.validate(&db).await?;
     let group = Group::by_id(&db, &group_id).await;
@@ -158,10 +147,11 @@ async fn api_update_group(mut req: Request<AppState>) -> tide::Result {
         if let Some(cover) = form.cover {
             group.cover = Some(cover);
         }
+        group.save(&db, None).await?;
         if let Some(managers) = form.managers {
-            group.managers = managers;
+            Membership::set_managers(&db, &group_id, &managers).await?;
+            group = Group::by_id(&db, &group_id).await.unwrap();
         }
-        group.save(&db, None).await?;
         Ok(group.to_response().into())
     } else {
         Ok(json_response(404, json!({
@@ -175,7 +165,7 @@ async fn api_update_group(mut req: Request<AppState>) -> tide::Result {
 }
 
 pub async fn api_delete_group_member(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let group_id = req.param("group_id").unwrap().to_owned();
@@ -184,7 +174,7 @@ pub async fn api_delete_group_member(req: Request<AppState>) -> tide::Result {
     let session: &Session = req.ext().unwrap();
     if let Some(group) = group {
         // 如果目标是小组管理员 则不能删除
-        if group.managers.contains(&user_id) {
+        if Membership::is_manager(&db, &user_id, &group_id).await {
             return Ok(json_response(400, json!({
                 "code": 1,
                 "message": {
@@ -194,7 +184,7 @@ pub async fn api_delete_group_member(req: Request<AppState>) -> tide::Result {
             })));
         }
         // 非小组管理员 只能删除自己
-        if session.permission == 1 && &user_id != session.user.as_ref().unwrap() {
+        if &user_id != session.user.as_ref().unwrap() && !is_group_manager(state, session, &group_id).await {
             return Ok(json_response(403, json!({
                 "code": 4,
                 "message": {
@@ -203,22 +193,18 @@ pub async fn api_delete_group_member(req: Request<AppState>) -> tide::Result {
                 }
             })));
         }
-        // 执行删除 将小组从user的groups中删除
-        let user = User::by_id(&db, &user_id).await;
-        if let Some(mut user) = user {
-            if let Some(mut groups) = user.groups.to_owned() {
-                groups.retain(|g| g != &group_id);
-                user.groups = Some(groups);
-                user.save(&db, None).await?;
-            } else {
+        // 执行删除 同时将小组从user的groups中删除
+        if User::by_id(&db, &user_id).await.is_some() {
+            if Membership::find_for(&db, &user_id, &group_id).await.is_none() {
                 return Ok(json_response(400, json!({
                     "code": 4,
                     "message": {
-                        "cn": "用户不属于任何小组",
-                        "en": "User not in any group"
+                        "cn": "用户不属于这个小组",
+                        "en": "User not in this group"
                     }
                 })));
             }
+            Membership::leave(&db, &user_id, &group_id).await?;
         } else {
             return Ok(json_response(404, json!({
                 "code": 4,
@@ -242,31 +228,35 @@ pub async fn api_delete_group_member(req: Request<AppState>) -> tide::Result {
 
 
 async fn api_create_invitation(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
+    let form: JoinInvitationForm = req.body_json().await?;
+    // 只能邀请别人加入自己管理的小组
+    for group_id in &form.groups {
+        require_group_manager(&req, group_id).await?;
+    }
     let state = req.state();
     let db = state.db.to_owned();
-    let form: JoinInvitationForm = req.body_json().await?;
+    let session: &Session = req.ext().unwrap();
     form.validate(&db).await?;
-    let code = random_string(10);
     let expire_at = chrono::DateTime::from_utc(
         chrono::NaiveDateTime::from_timestamp(form.expire_at, 0),
         chrono::Utc,
     );
-    let mut invitation = Invitation {
-        id: None,
-        code: code.to_owned(),
-        expire_at: expire_at.into(),
-        groups: Some(form.groups),
-        permission: form.permission,
-    };
+    // 小组邀请默认只能使用一次
+    let mut invitation = Invitation::new(random_string(10),
+                                         session.user.as_ref().unwrap(),
+                                         expire_at.into(),
+                                         Some(form.groups),
+                                         form.permission,
+                                         Some(form.max_uses.unwrap_or(1)));
     invitation.save(&db, None).await?;
     return Ok(json!({
-        "code": code
+        "code": invitation.code
     }).into());
 }
 
 async fn api_check_invitation(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let code = req.param("code").unwrap().to_owned();
@@ -285,7 +275,7 @@ async fn api_check_invitation(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_apply_invitation(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let code = req.param("code").unwrap().to_owned();
@@ -293,8 +283,9 @@ async fn api_apply_invitation(req: Request<AppState>) -> tide::Result {
     return if let Some(invitation) = invitation {
         let session: &Session = req.ext().unwrap();
         let user = User::by_id(&db, session.user.as_ref().unwrap()).await;
-        if let Some(mut user) = user {
-            if user.groups.is_some() {
+        if let Some(user) = user {
+            // 退出小组后groups可能是空列表
+            if user.groups.as_ref().is_some_and(|groups| !groups.is_empty()) {
                 return Ok(json_response(400, json!({
                     "code": 1003,
                     "message": {
@@ -303,21 +294,27 @@ async fn api_apply_invitation(req: Request<AppState>) -> tide::Result {
                     }
                 })));
             }
-            let mut groups = user.groups.to_owned().unwrap_or(vec![]);
-            groups.extend(invitation.groups.clone().unwrap());
-            user.groups = Some(groups.clone());
-            user.save(&db, None).await?;
-            if invitation.permission == 2 {
-                user.permission = 2.0;
-                // 将用户加入小组的管理员列表中
-                for group_id in invitation.groups.as_ref().unwrap_or(&vec![]) {
-                    let mut group = Group::by_id(&db, group_id).await.unwrap();
-                    group.managers.push(user.id.to_owned().unwrap().to_hex());
-                    group.save(&db, None).await?;
-                }
-                user.save(&db, None).await?;
+            let uid = user.id.as_ref().unwrap().to_hex();
+            // 先占用一次使用次数 并发使用同一个邀请时不会超出限制
+            if Invitation::redeem(&db, invitation.id.as_ref().unwrap(), &uid).await?.is_none() {
+                return Ok(json_response(404, json!({
+                    "code": 1001,
+                    "message": {
+                        "cn": "邀请不存在",
+                        "en": "Invitation not found"
+                    }
+                })));
+            }
+            let groups = invitation.groups.clone().unwrap_or(vec![]);
+            // 只在邀请的小组中担任管理员 不修改全局的权限和角色
+            let role = if invitation.permission == 2 {
+                memberships::MANAGER
+            } else {
+                memberships::MEMBER
+            };
+            for group_id in &groups {
+                Membership::join(&db, &uid, group_id, role).await?;
             }
-            invitation.delete(&db).await?;
             let mut resp = Vec::new();
             for group_id in groups {
                 let group = Group::by_id(&db, &group_id).await.unwrap();
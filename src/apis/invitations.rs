@@ -0,0 +1,79 @@
+// 管理注册邀请和小组邀请
+use log::info;
+use serde_json::json;
+use tide::{Request, Server};
+use crate::apis::{has_capability, is_group_manager, json_response, require_capability, require_group_manager};
+use crate::AppState;
+use crate::roles;
+use crate::errors::AppErrors;
+use crate::forms::users::InvitationsQuery;
+use crate::models::{SearchById, Session};
+use crate::models::invitations::Invitation;
+use wither::Model;
+
+pub fn register(app: &mut Server<AppState>) {
+    info!("注册API invitations");
+    app.at("/invitations").get(api_get_invitations);
+    app.at("/invitations/:id").delete(api_revoke_invitation);
+}
+
+async fn api_get_invitations(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let query: InvitationsQuery = req.query()?;
+    let state = req.state();
+    let db = &state.db;
+    let session: &Session = req.ext().unwrap();
+    let uid = session.user.as_ref().unwrap();
+    let invitations = if let Some(group) = &query.group {
+        require_group_manager(&req, group).await?;
+        Invitation::by_group(db, group).await?
+    } else {
+        let creator = query.creator.as_ref().unwrap_or(uid);
+        // 只有管理员可以查看其他人创建的邀请
+        if creator != uid && !has_capability(state, session, roles::USERS_ADMIN) {
+            return Err(AppErrors::MissingCapability(roles::USERS_ADMIN.to_owned()).into());
+        }
+        Invitation::by_creator(db, creator).await?
+    };
+    let result: Vec<_> = invitations.into_iter().map(|i| i.to_detail()).collect();
+    Ok(json!(result).into())
+}
+
+async fn api_revoke_invitation(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let db = &state.db;
+    let session: &Session = req.ext().unwrap();
+    let id = req.param("id").unwrap().to_owned();
+    let mut invitation = match Invitation::by_id(db, &id).await {
+        Some(invitation) => invitation,
+        None => return Ok(json_response(404, json!({
+            "code": 1001,
+            "message": {
+                "cn": "邀请不存在",
+                "en": "Invitation not found"
+            }
+        }))),
+    };
+    // 创建者 用户管理员 以及邀请涉及的小组的管理员可以撤销
+    let mut allowed = invitation.creator.as_ref() == session.user.as_ref()
+        || has_capability(state, session, roles::USERS_ADMIN);
+    for group_id in invitation.groups.as_ref().unwrap_or(&vec![]) {
+        if allowed {
+            break;
+        }
+        allowed = is_group_manager(state, session, group_id).await;
+    }
+    if !allowed {
+        return Ok(json_response(403, json!({
+            "code": 1,
+            "message": {
+                "cn": "您没有权限撤销这个邀请",
+                "en": "You have no permission to revoke this invitation"
+            }
+        })));
+    }
+    invitation.revoked = true;
+    invitation.save(db, None).await?;
+    Ok(invitation.to_detail().into())
+}
@@ -6,6 +6,8 @@ mod positions;
 mod groups;
 mod projects;
 mod storage;
+mod video;
+mod invitations;
 // mod data;
 
 use log::info;
@@ -14,6 +16,8 @@ use tide::prelude::json;
 
 use crate::AppState;
 use crate::errors::AppErrors;
+use crate::models::api_tokens::scope_capabilities;
+use crate::models::memberships::Membership;
 
 
 pub fn register(app: &mut Server<AppState>) {
@@ -35,15 +39,63 @@ pub fn register(app: &mut Server<AppState>) {
     groups::register(app);
     storage::register(app);
     projects::register(app);
+    video::register(app);
+    invitations::register(app);
 }
 
 
-pub async fn require_perm(req: &tide::Request<AppState>, allowed: Vec<i8>) -> Result<(), AppErrors> {
+pub async fn require_capability(req: &tide::Request<AppState>, capability: &str) -> Result<(), AppErrors> {
     let session: &crate::models::Session = req.ext().unwrap();
-    if allowed.contains(&session.permission) {
+    if has_capability(req.state(), session, capability) {
         Ok(())
     } else {
-        Err(AppErrors::CrossPermissionError(session.permission, allowed))
+        Err(AppErrors::MissingCapability(capability.to_owned()))
+    }
+}
+
+// 客户端的IP 用于按IP限制请求
+// 转发头可以由客户端任意设置 只有在可信的反向代理之后才使用
+pub fn client_ip(req: &Request<AppState>) -> String {
+    let addr = if req.state().config.server.trusted_proxy {
+        req.remote()
+    } else {
+        req.peer_addr()
+    };
+    let addr = addr.unwrap_or("-");
+    // 去掉端口 同一个客户端的每个连接端口都不同
+    match addr.parse::<std::net::SocketAddr>() {
+        Ok(socket) => socket.ip().to_string(),
+        Err(..) => addr.to_owned(),
+    }
+}
+
+// 不需要直接拒绝请求时使用 例如管理员可以修改他人的数据
+// 使用API令牌时 能力还要在令牌的权限范围之内
+pub fn has_capability(state: &AppState, session: &crate::models::Session, capability: &str) -> bool {
+    let in_scope = session.scopes.as_ref().is_none_or(|scopes| {
+        scopes.iter().any(|s| scope_capabilities(s).contains(&capability))
+    });
+    in_scope && state.config.roles.allows(session.role_name(), capability)
+}
+
+// 要求当前用户是某个小组的管理员
+// 拥有 groups:admin 能力的角色可以管理所有小组
+pub async fn require_group_manager(req: &tide::Request<AppState>, group_id: &str) -> Result<(), AppErrors> {
+    let session: &crate::models::Session = req.ext().unwrap();
+    if is_group_manager(req.state(), session, group_id).await {
+        Ok(())
+    } else {
+        Err(AppErrors::NotGroupManager(group_id.to_owned()))
+    }
+}
+
+pub async fn is_group_manager(state: &AppState, session: &crate::models::Session, group_id: &str) -> bool {
+    if has_capability(state, session, crate::roles::GROUPS_ADMIN) {
+        return true;
+    }
+    match &session.user {
+        Some(uid) if session.login => Membership::is_manager(&state.db, uid, group_id).await,
+        _ => false,
     }
 }
 
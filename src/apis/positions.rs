@@ -6,8 +6,9 @@
 use log::info;
 use serde_json::json;
 use tide::{Request, Server};
-use crate::apis::{json_response, require_perm};
+use crate::apis::{json_response, require_capability, require_group_manager};
 use crate::AppState;
+use crate::roles;
 use crate::forms::positions::{NewPositionForm, UpdatePositionForm};
 use crate::models::positions::Position;
 use crate::models::{SearchById, Session};
@@ -27,8 +28,8 @@ pub fn register(app: &mut Server<AppState>) {
 }
 
 // rust版本特有的API 获取一个用户可用的调查点
-async fn api_get_available_positions(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![1, 2, 3]).await?;
+async fn api_get_available_positions(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::RECORDS_READ).await?;
     let state = req.state();
     let db = state.db.clone();
     let session: &Session = req.ext().unwrap();
@@ -47,8 +48,8 @@ async fn api_get_available_positions(mut req: Request<AppState>) -> tide::Result
     Ok(json!(result).into())
 }
 
-async fn api_get_position(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![1, 2, 3]).await?;
+async fn api_get_position(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::RECORDS_READ).await?;
     let id = req.param("id")?;
     let state = req.state();
     let db = state.db.to_owned();
@@ -67,13 +68,13 @@ async fn api_get_position(mut req: Request<AppState>) -> tide::Result {
 
 
 async fn api_put_position(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![2, 3]).await?;
     let form: UpdatePositionForm = req.body_json().await?;
     let id = req.param("id")?;
     let state = req.state();
     let db = state.db.to_owned();
 
     if let Some(mut position) = Position::by_id(&db, &id.to_string()).await {
+        require_group_manager(&req, &position.belongs_to).await?;
         position.name = form.name;
         position.longitude = form.longitude;
         position.latitude = form.latitude;
@@ -92,10 +93,10 @@ async fn api_put_position(mut req: Request<AppState>) -> tide::Result {
 
 
 async fn api_new_position(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![2, 3]).await?;
+    let form: NewPositionForm = req.body_json().await?;
+    require_group_manager(&req, &form.group_id).await?;
     let state = req.state();
     let db = state.db.to_owned();
-    let form: NewPositionForm = req.body_json().await?;
     form.validate(&db).await?;
     let mut position = Position {
         id: None,
@@ -109,8 +110,8 @@ async fn api_new_position(mut req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_replace_by_group(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![2, 3]).await?;
     let group_id = req.param("id").unwrap().to_owned();
+    require_group_manager(&req, &group_id).await?;
     let state = req.state();
     let db = state.db.to_owned();
 
@@ -169,8 +170,8 @@ async fn api_replace_by_group(mut req: Request<AppState>) -> tide::Result {
     Ok(json!(positions).into())
 }
 
-async fn api_get_by_group(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![1, 2, 3]).await?;
+async fn api_get_by_group(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::RECORDS_READ).await?;
     let group_id = req.param("id").unwrap().to_owned();
     let state = req.state();
     let db = state.db.to_owned();
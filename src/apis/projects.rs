@@ -1,6 +1,7 @@
 use tide::{Request, Server};
-use crate::apis::{json_response, require_perm};
+use crate::apis::{json_response, require_capability};
 use crate::AppState;
+use crate::roles;
 use crate::models::projects::Project;
 use futures::StreamExt;
 use log::info;
@@ -24,7 +25,7 @@ pub fn register(app: &mut Server<AppState>) {
 }
 
 async fn api_get_projects(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_READ).await?;
     let state = req.state();
     let db = state.db.clone();
     let projects: Vec<_> = Project::find(&db, None, None)
@@ -42,7 +43,7 @@ async fn api_get_projects(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_get_running_project(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_READ).await?;
     let state = req.state();
     let db = state.db.clone();
     if let Some(project) = Project::get_running_project(&db).await {
@@ -69,7 +70,7 @@ async fn set_running(db: &Database, project: &mut Project) -> tide::Result<()> {
 }
 
 async fn api_create_project(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![3]).await?;
+    require_capability(&req, roles::PROJECTS_ADMIN).await?;
     let form: NewProjectForm = req.body_json().await?;
     let state = req.state();
     let db = state.db.clone();
@@ -93,7 +94,7 @@ async fn api_create_project(mut req: Request<AppState>) -> tide::Result {
 
 
 // async fn api_get_running_projects(req: Request<AppState>) -> tide::Result {
-//     require_perm(&req, vec![1, 2, 3]).await?;
+//     require_capability(&req, roles::RECORDS_READ).await?;
 //     let state = req.state();
 //     let db = state.db.clone();
 //     let projects: Vec<_> = Project::find(&db, Some(doc! {
@@ -113,7 +114,7 @@ async fn api_create_project(mut req: Request<AppState>) -> tide::Result {
 // }
 
 async fn api_get_project(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_READ).await?;
     let id = req.param("id").unwrap();
     let state = req.state();
     let db = state.db.clone();
@@ -131,7 +132,7 @@ async fn api_get_project(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_delete_project(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![3]).await?;
+    require_capability(&req, roles::PROJECTS_ADMIN).await?;
     let id = req.param("id").unwrap();
     let state = req.state();
     let db = state.db.clone();
@@ -151,7 +152,7 @@ async fn api_delete_project(req: Request<AppState>) -> tide::Result {
 
 
 async fn api_update_project(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![3]).await?;
+    require_capability(&req, roles::PROJECTS_ADMIN).await?;
     let id = req.param("id").unwrap().to_owned();
     let state = req.state();
     let db = state.db.clone();
@@ -2,13 +2,13 @@ use log::info;
 use serde_json::json;
 use tide::{Request, Server};
 use wither::bson::doc;
-use crate::apis::{json_response, require_perm};
+use crate::apis::{has_capability, is_group_manager, json_response, require_capability};
 use crate::AppState;
+use crate::roles;
 use crate::forms::records::{NewRecordForm, RecordsQuery, UpdateDraftForm, UpdateRecordForm};
 use crate::models::records::Record;
 use crate::models::{SearchById, Session};
 use crate::models::drafts::RecordDraft;
-use crate::models::groups::Group;
 use crate::models::projects::Project;
 use wither::Model;
 use futures::StreamExt;
@@ -33,8 +33,8 @@ pub fn register(app: &mut Server<AppState>) {
         .delete(api_delete_record_draft);
 }
 
-async fn api_get_records_count(mut req: tide::Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![1, 2, 3]).await?;
+async fn api_get_records_count(req: tide::Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::RECORDS_READ).await?;
     // 获取全站记录数
     let db = req.state().db.to_owned();
     if let Ok(count) = db.collection("records")
@@ -47,7 +47,7 @@ async fn api_get_records_count(mut req: tide::Request<AppState>) -> tide::Result
 }
 
 async fn api_get_record_by_id(req: tide::Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_READ).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let id = req.param("id")?.to_owned();
@@ -66,7 +66,7 @@ async fn api_get_record_by_id(req: tide::Request<AppState>) -> tide::Result {
 
 
 async fn api_get_records(req: tide::Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_READ).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let query: RecordsQuery = req.query()?;
@@ -86,7 +86,7 @@ async fn api_get_records(req: tide::Request<AppState>) -> tide::Result {
 
 
 async fn api_create_record(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_WRITE).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let form: NewRecordForm = req.body_json().await?;
@@ -132,7 +132,7 @@ async fn api_create_record(mut req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_update_record_by_id(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_WRITE).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let id = req.param("id")?.to_owned();
@@ -151,28 +151,17 @@ async fn api_update_record_by_id(mut req: Request<AppState>) -> tide::Result {
     }
     let mut record = record.unwrap();
     // 如果非管理员 并且是自己的记录，则允许修改
-    if session.user.as_ref().unwrap() != &record.user {
-        if session.permission == 2 {
-            // 检查是否为小组长
-            let group = Group::by_id(&db, &record.group).await.unwrap();
-            if !group.managers.contains(session.user.as_ref().unwrap()) {
-                return Ok(json_response(403, json!({
-                    "code": 1,
-                    "message": {
-                        "cn": "您没有权限修改该记录",
-                        "en": "You have no permission to update this record"
-                    }
-                })));
+    // 小组长可以修改本小组的记录
+    if !has_capability(req.state(), session, roles::RECORDS_ADMIN)
+        && session.user.as_ref().unwrap() != &record.user
+        && !is_group_manager(req.state(), session, &record.group).await {
+        return Ok(json_response(403, json!({
+            "code": 1,
+            "message": {
+                "cn": "您没有权限修改该记录",
+                "en": "You have no permission to update this record"
             }
-        } else if session.permission == 1 {
-            return Ok(json_response(403, json!({
-                "code": 1,
-                "message": {
-                    "cn": "您没有权限修改该记录",
-                    "en": "You have no permission to update this record"
-                }
-            })));
-        }
+        })));
     }
     // 执行修改
     if let Some(num) = form.num {
@@ -226,7 +215,7 @@ async fn api_update_record_by_id(mut req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_delete_record_by_id(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_WRITE).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let id = req.param("id")?.to_owned();
@@ -243,28 +232,17 @@ async fn api_delete_record_by_id(req: Request<AppState>) -> tide::Result {
     }
     let record = record.unwrap();
     // 如果非管理员 并且是自己的记录，则允许修改
-    if session.user.as_ref().unwrap() != &record.user {
-        if session.permission == 2 {
-            // 检查是否为小组长
-            let group = Group::by_id(&db, &record.group).await.unwrap();
-            if !group.managers.contains(session.user.as_ref().unwrap()) {
-                return Ok(json_response(403, json!({
-                    "code": 1,
-                    "message": {
-                        "cn": "您没有权限删除该记录",
-                        "en": "You have no permission to delete this record"
-                    }
-                })));
+    // 小组长可以删除本小组的记录
+    if !has_capability(req.state(), session, roles::RECORDS_ADMIN)
+        && session.user.as_ref().unwrap() != &record.user
+        && !is_group_manager(req.state(), session, &record.group).await {
+        return Ok(json_response(403, json!({
+            "code": 1,
+            "message": {
+                "cn": "您没有权限删除该记录",
+                "en": "You have no permission to delete this record"
             }
-        } else if session.permission == 1 {
-            return Ok(json_response(403, json!({
-                "code": 1,
-                "message": {
-                    "cn": "您没有权限删除该记录",
-                    "en": "You have no permission to delete this record"
-                }
-            })));
-        }
+        })));
     }
     // 执行删除
     record.delete(&db).await?;
@@ -273,7 +251,7 @@ async fn api_delete_record_by_id(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_get_records_by_user_id(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_READ).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let user_id = req.param("id")?.to_owned();
@@ -293,7 +271,7 @@ async fn api_get_records_by_user_id(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_get_record_draft(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_WRITE).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let session: &Session = req.ext().unwrap();
@@ -305,7 +283,7 @@ async fn api_get_record_draft(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_update_record_draft(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_WRITE).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let session: &Session = req.ext().unwrap();
@@ -345,7 +323,7 @@ async fn api_update_record_draft(mut req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_delete_record_draft(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_WRITE).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let session: &Session = req.ext().unwrap();
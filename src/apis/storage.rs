@@ -1,8 +1,9 @@
 use log::info;
 use serde_json::json;
 use tide::{Body, Request, Response, Server};
-use crate::apis::{json_response, require_perm};
+use crate::apis::{json_response, require_capability};
 use crate::AppState;
+use crate::roles;
 use crate::models::{SearchById, Session};
 use crate::models::storage::Storage;
 use wither::Model;
@@ -33,6 +34,12 @@ pub struct BufferedBytesStream<T> {
     inner: T,
 }
 
+impl<T> BufferedBytesStream<T> {
+    pub fn new(inner: T) -> Self {
+        BufferedBytesStream { inner }
+    }
+}
+
 impl<T: Read + Unpin> Stream for BufferedBytesStream<T> {
     type Item = async_std::io::Result<Vec<u8>>;
 
@@ -50,7 +57,7 @@ impl<T: Read + Unpin> Stream for BufferedBytesStream<T> {
     }
 }
 
-fn random_filename(origin_ext: String) -> String {
+pub fn random_filename(origin_ext: String) -> String {
     use rand::Rng;
     use std::time::SystemTime;
     use std::time::UNIX_EPOCH;
@@ -65,8 +72,8 @@ fn random_filename(origin_ext: String) -> String {
     format!("{}-{}.{}", timestamp, random_number, origin_ext)
 }
 
-pub async fn api_upload(mut req: Request<AppState>) -> tide::Result {
-    require_perm(&mut req, vec![0, 1, 2, 3]).await?;
+pub async fn api_upload(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::STORAGE_UPLOAD).await?;
     let state = req.state().to_owned();
     let session: &Session = req.ext().unwrap();
     let session = session.to_owned();
@@ -278,7 +285,7 @@ async fn api_download_inline_resized(req: Request<AppState>) -> tide::Result {
 }
 
 async fn api_get_info(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::RECORDS_READ).await?;
     let state = req.state();
     let db = state.db.to_owned();
     let id = req.param("id").unwrap().to_owned();
@@ -10,6 +10,7 @@ use tide::Server;
 use crate::AppState;
 use serde::Deserialize;
 use wither::bson::doc;
+use crate::passwords::client_digest;
 
 pub fn register(app: &mut Server<AppState>) {
     info!("注册API system");
@@ -26,12 +27,8 @@ struct EncryptForm {
 
 async fn api_encrypt(mut req: tide::Request<AppState>) -> tide::Result {
     let form: EncryptForm = req.body_json().await?;
-    let content = form.content;
-    // 加
-    let content = format!("ここで振り返る{}もうすぐだよ{}知らない世界も{}歩いてみよう", content, content, content);
-
     Ok(json!({
-        "encrypted": format!("{:x}", md5::compute(content.as_bytes()))
+        "encrypted": client_digest(&form.content)
     }).into())
 }
 
@@ -39,8 +36,10 @@ async fn api_online_num(req: tide::Request<AppState>) -> tide::Result {
     let db = req.state().db.to_owned();
     let coll = db.collection("sessions");
 
-    let session_num = coll.count_documents(None, None).await?;
-    let login_num = coll.count_documents(Some(doc! {"login": true}), None).await?;
+    // TTL索引的清理有延迟 这里只统计尚未过期的session
+    let now = chrono::Utc::now();
+    let session_num = coll.count_documents(Some(doc! {"expire_at": {"$gt": now}}), None).await?;
+    let login_num = coll.count_documents(Some(doc! {"login": true, "expire_at": {"$gt": now}}), None).await?;
 
     Ok(json!({
         "sessions": session_num,
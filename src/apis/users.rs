@@ -5,23 +5,37 @@
 // =====================
 
 
-use log::info;
+use log::{info, warn};
 use rand::Rng;
 use serde_json::{json, Value};
 use tide::{Request, Response, Server, StatusCode};
 
-use crate::apis::{json_response, require_perm};
+use crate::apis::{client_ip, has_capability, json_response, require_capability, require_group_manager};
 use crate::AppState;
+use crate::roles;
 use crate::errors::AppErrors;
-use crate::forms::users::{CreateInactiveUserForm, LoginForm, NewInvitationForm, NewUserForm, NewUserFromInactive, UpdateUserForm};
+use crate::forms::users::{CodeForm, CreateInactiveUserForm, DeleteUserQuery, EmailChangeForm, LoginForm, NewApiTokenForm, NewInvitationForm, NewUserForm, NewUserFromInactive, PasswordChangeForm, PasswordResetConfirmForm, PasswordResetForm, ResendCodeForm, UpdateUserForm, UsersQuery};
 use crate::models::inactive_users::InactiveUser;
 use crate::models::{SearchById, Session};
 
 use wither::Model;
 use crate::models::invitations::Invitation;
-use crate::models::users::User;
+use crate::models::users::{AvatarSize, User};
+use crate::models::storage::Storage;
+use crate::apis::storage::{random_filename, BufferedBytesStream};
+use crate::avatars;
 use futures::StreamExt;
-use crate::models::groups::Group;
+use crate::models::memberships::{self, Membership};
+use crate::models::login_attempts::LoginAttempt;
+use crate::models::api_tokens::ApiToken;
+use crate::models::password_resets::PasswordReset;
+use crate::models::email_changes::EmailChange;
+use crate::models::export_jobs::ExportJob;
+use tide::Body;
+use wither::bson::doc;
+use wither::bson::oid::ObjectId;
+use wither::mongodb::options::FindOptions;
+use crate::passwords::{dummy_verify, hash_password, is_legacy, verify_password};
 
 pub fn register(app: &mut Server<AppState>) {
     info!("注册用户API");
@@ -37,66 +51,132 @@ pub fn register(app: &mut Server<AppState>) {
         .post(api_login);
     app.at("/users/logout")
         .get(api_logout);
+    app.at("/users/password_reset")
+        .post(api_request_password_reset);
+    app.at("/users/password_reset/confirm")
+        .post(api_confirm_password_reset);
+    app.at("/users/me/export")
+        .get(api_export_my_data);
+    app.at("/users/me/export/download")
+        .get(api_download_my_export);
+    app.at("/users/me/password")
+        .post(api_change_password);
+    app.at("/users/me/email")
+        .post(api_request_email_change);
+    app.at("/users/me/email/confirm")
+        .post(api_confirm_email_change);
+    app.at("/users/me/2fa")
+        .post(api_enroll_two_factor);
+    app.at("/users/me/2fa/confirm")
+        .post(api_confirm_two_factor);
+    app.at("/users/me/2fa/disable")
+        .post(api_disable_two_factor);
+    app.at("/users/me/sessions")
+        .get(api_get_my_sessions)
+        .delete(api_revoke_my_sessions);
+    app.at("/users/me/sessions/:id")
+        .delete(api_revoke_my_session);
+    app.at("/users/me/avatar")
+        .post(api_upload_avatar);
+    app.at("/users/me/tokens")
+        .get(api_get_my_tokens)
+        .post(api_create_token);
+    app.at("/users/me/tokens/:id")
+        .delete(api_revoke_token);
+    app.at("/users/lockouts")
+        .get(api_get_lockouts);
+    app.at("/users/lockouts/:id")
+        .delete(api_clear_lockout);
     app.at("/users/register_invitations")
         .post(api_new_register_invitation);
     app.at("/users/register_invitations/:code")
         .get(api_get_register_invitation);
+    app.at("/users/:id/sessions")
+        .delete(api_revoke_user_sessions);
     app.at("/users/:id")
         .get(api_get_user)
         .patch(api_update_user)
         .delete(api_delete_user);
     app.at("/users/inactive")
         .post(api_create_inactive_user);
-    app.at("/users/recovery")
-        .post(api_recovery_user);
+    app.at("/users/inactive/resend")
+        .post(api_resend_inactive_code);
 }
 
 
 async fn api_create_user(mut req: Request<AppState>) -> tide::Result<Response> {
-    require_perm(&req, vec![0, 3]).await?;
     let state = req.state();
     let db = state.db.clone();
     let session: &Session = req.ext().unwrap();
-    if session.permission == 0 {
+    if !session.login {
         // 普通用户
         let form: NewUserFromInactive = req.body_json().await?;
-        form.validate(&db).await?;
-
-        if let Some(inactive_user) = InactiveUser::by_code(&db, form.code.clone()).await {
-            let mut user = inactive_user.to_user();
+        // 验证码错误时增加尝试次数 超过次数后需要重新发送
+        if let Some(inactive_user) = InactiveUser::claim(&db, &form.email, &form.code).await? {
+            let mut user = inactive_user.clone().to_user();
             // 将其保存到数据库
 
             info!("新注册用户: {}", user.name);
-            user.save(&db, None).await?;
-            for group_id in user.groups.as_ref().unwrap_or(&vec![]) {
-                // 如果是管理员 则将其加入管理员组
-                if user.permission == 2.0 || user.permission == 3.0 {
-                    let mut group = Group::by_id(&db, &group_id).await.unwrap();
-                    group.managers.push(user.id.as_ref().unwrap().to_hex());
-                    group.save(&db, None).await?;
+            if let Err(e) = user.save(&db, None).await {
+                inactive_user.release(&db).await?;
+                return Err(e.into());
+            }
+            // 用户保存成功后才计入邀请的使用次数 邀请失效时撤销注册
+            if let Some(invitation) = &inactive_user.invitation {
+                let uid = user.id.as_ref().unwrap().to_hex();
+                let redeemed = match ObjectId::with_string(invitation) {
+                    Ok(oid) => Invitation::redeem(&db, &oid, &uid).await,
+                    Err(..) => Ok(None),
+                };
+                if !matches!(redeemed, Ok(Some(..))) {
+                    user.delete(&db).await?;
+                    inactive_user.release(&db).await?;
+                    redeemed?;
+                    return Err(AppErrors::ValidationError(json!({
+                        "code": 4,
+                        "message": {
+                            "cn": "邀请已失效",
+                            "en": "Invitation is no longer valid"
+                        }
+                    })).into());
                 }
             }
-            InactiveUser::by_code(&db, form.code).await.unwrap().delete(&db).await?;
+            // 用户保存成功后才删除注册请求
+            inactive_user.consume(&db).await?;
+            // 如果是管理员 则成为小组的管理员
+            let role = if user.permission == 2.0 || user.permission == 3.0 {
+                memberships::MANAGER
+            } else {
+                memberships::MEMBER
+            };
+            let uid = user.id.as_ref().unwrap().to_hex();
+            for group_id in user.groups.as_ref().unwrap_or(&vec![]) {
+                Membership::join(&db, &uid, group_id, role).await?;
+            }
             Ok(user.to_response(&db).await.into())
         } else {
             Err(AppErrors::ValidationError(json!({
                 "code": 4,
                 "message": {
-                    "cn": "无效的验证码",
-                    "en": "Invalid verification code"
+                    "cn": "验证码错误或已过期",
+                    "en": "Invalid or expired verification code"
                 }
             })).into())
         }
-    } else if session.permission == 3 {
+    } else if has_capability(state, session, roles::USERS_ADMIN) {
         // 管理员
         let form: NewUserForm = req.body_json().await?;
         form.validate(&db).await?;
         let mut user = form.to_user();
         // 将其保存到数据库
         user.save(&db, None).await?;
+        let uid = user.id.as_ref().unwrap().to_hex();
+        for group_id in user.groups.as_ref().unwrap_or(&vec![]) {
+            Membership::join(&db, &uid, group_id, memberships::MEMBER).await?;
+        }
         Ok(user.to_response(&db).await.into())
     } else {
-        Err(AppErrors::CrossPermissionError(session.permission, vec![0, 3]).into())
+        Err(AppErrors::MissingCapability(roles::USERS_ADMIN.to_owned()).into())
     }
 }
 
@@ -156,46 +236,171 @@ async fn api_check_name(mut req: Request<AppState>) -> tide::Result<Response> {
     }
 }
 
+// 登录尝试过于频繁时的响应
+fn too_many_attempts(retry_after: i64) -> Response {
+    let mut resp = json_response(429, json!({
+        "code": 1005,
+        "message": {
+            "cn": "尝试次数过多 请稍后再试",
+            "en": "Too many attempts, please try again later"
+        },
+        "description": {
+            "retry_after": retry_after
+        }
+    }));
+    resp.insert_header("Retry-After", retry_after.to_string());
+    resp
+}
+
+// 密码正确 但还需要两步验证码
+fn two_factor_required() -> Response {
+    json_response(401, json!({
+        "code": 1006,
+        "message": {
+            "cn": "需要两步验证码",
+            "en": "Two-factor code required"
+        }
+    }))
+}
+
 async fn api_login(mut req: Request<AppState>) -> tide::Result<Response> {
-    let state = req.state();
+    let state = req.state().to_owned();
     let db = state.db.clone();
     let session: &Session = req.ext().unwrap();
     let mut session = session.to_owned();
+    let ip_key = LoginAttempt::ip_key(&client_ip(&req));
+    let login_config = &state.config.login;
 
     let form: LoginForm = req.body_json().await?;
-    form.validate(&db).await?;
-    // 已经验证过了，用户一定存在
-    let user = User::by_id(&db, &form.id).await.unwrap();
-    if user.password == form.password {
+    if let Some(attempt) = LoginAttempt::by_key(&db, &ip_key).await {
+        if let Some(retry_after) = attempt.retry_after() {
+            return Ok(too_many_attempts(retry_after));
+        }
+    }
+    if let Err(e) = form.validate() {
+        LoginAttempt::record_failure(&db, &ip_key, login_config.max_failures_per_ip, login_config).await?;
+        return Err(e.into());
+    }
+    // 邮箱可能对应多个用户 逐个尝试密码
+    // 被锁定的账号不校验密码 与不存在的账号一样返回账号或密码错误 避免泄露账号是否存在
+    let mut candidates = Vec::new();
+    for candidate in form.candidates(&db).await {
+        let user_key = LoginAttempt::user_key(&candidate.id.as_ref().unwrap().to_hex());
+        let locked = LoginAttempt::by_key(&db, &user_key).await
+            .is_some_and(|attempt| attempt.retry_after().is_some());
+        if !locked {
+            candidates.push(candidate);
+        }
+    }
+    if candidates.is_empty() {
+        // 即使用户不存在也计算一次哈希 使响应时间保持一致
+        dummy_verify(&form.password);
+    }
+    let (matched, others): (Vec<User>, Vec<User>) = candidates.into_iter()
+        .partition(|user| verify_password(&user.password, &form.password));
+    if let Some(mut user) = matched.into_iter().next() {
+        let user_key = LoginAttempt::user_key(&user.id.as_ref().unwrap().to_hex());
+        if user.totp_enabled {
+            // 第二步 校验两步验证码
+            let passed = match &form.totp {
+                Some(code) => user.verify_second_factor(&db, code).await?,
+                None => return Ok(two_factor_required()),
+            };
+            if !passed {
+                LoginAttempt::record_failure(&db, &ip_key, login_config.max_failures_per_ip, login_config).await?;
+                LoginAttempt::record_failure(&db, &user_key, login_config.max_failures, login_config).await?;
+                return Err(AppErrors::ValidationError(json!({
+                    "code": 4,
+                    "message": {
+                        "cn": "两步验证码错误",
+                        "en": "Two-factor code is incorrect"
+                    }
+                })).into());
+            }
+        }
+        LoginAttempt::clear(&db, &user_key).await?;
+        if is_legacy(&user.password) {
+            // 旧版的MD5密码 迁移为Argon2
+            info!("迁移用户 {} 的密码哈希", user.name);
+            user.password = hash_password(&form.password);
+            user.save(&db, None).await?;
+        }
         // 修改Session
-        session.login = true;
-        session.permission = user.permission as i8;
-        session.user = Some(user.id.as_ref().unwrap().to_hex());
+        session.grant(&user, &state.config);
+        session.rotate();
         session.save(&db, None).await?;
+        let mut body = user.to_response(&db).await;
+        if session.role_name() != user.role_name() {
+            // 需要先启用两步验证才能获得完整的权限
+            body["two_factor_setup_required"] = json!(true);
+        }
         let mut resp = Response::new(200);
-        resp.set_body(user.to_response(&db).await);
+        resp.set_body(body);
+        resp.insert_ext(session);
         Ok(resp)
     } else {
+        LoginAttempt::record_failure(&db, &ip_key, login_config.max_failures_per_ip, login_config).await?;
+        for user in others {
+            let user_key = LoginAttempt::user_key(&user.id.as_ref().unwrap().to_hex());
+            let attempt = LoginAttempt::record_failure(&db, &user_key, login_config.max_failures, login_config).await?;
+            if attempt.locked {
+                warn!("用户 {} 连续登录失败 {} 次 已被锁定", user.name, attempt.failures);
+            }
+        }
+        // 用户不存在和密码错误返回相同的结果
         Err(AppErrors::ValidationError(json!({
             "code": 4,
             "message": {
-                "cn": "邮箱或密码错误",
-                "en": "Email or password is incorrect"
+                "cn": "账号或密码错误",
+                "en": "Account or password is incorrect"
             }
         })).into())
     }
 }
 
+// 列出所有处于退避或锁定状态的账号和IP
+async fn api_get_lockouts(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::USERS_ADMIN).await?;
+    let db = req.state().db.clone();
+    let attempts: Vec<_> = LoginAttempt::find(&db, Some(doc! {
+        "blocked_until": {"$gt": chrono::Utc::now()}
+    }), None)
+        .await?
+        .collect()
+        .await;
+    let mut result = Vec::new();
+    for attempt in attempts.into_iter().flatten() {
+        result.push(attempt.to_response());
+    }
+    Ok(json!(result).into())
+}
+
+async fn api_clear_lockout(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::USERS_ADMIN).await?;
+    let db = req.state().db.clone();
+    let id = req.param("id")?.to_owned();
+    if let Some(attempt) = LoginAttempt::by_id(&db, &id).await {
+        info!("解除锁定 {}", attempt.key);
+        attempt.delete(&db).await?;
+        Ok(Response::new(StatusCode::NoContent))
+    } else {
+        Ok(json_response(404, json!({
+            "code": 4,
+            "message": {
+                "cn": "锁定记录不存在",
+                "en": "Lockout not found"
+            }
+        })))
+    }
+}
+
 async fn api_logout(req: Request<AppState>) -> tide::Result<Response> {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.clone();
     let session: &Session = req.ext().unwrap();
-    let mut session = session.to_owned();
-    session.login = false;
-    session.permission = 0;
-    session.user = None;
-    session.save(&db, None).await?;
+    // 注销后不再需要保存匿名的session 直接删除即可
+    session.to_owned().delete(&db).await?;
     let mut resp = Response::new(200);
     resp.set_body(json!({
         "code": 0,
@@ -207,6 +412,451 @@ async fn api_logout(req: Request<AppState>) -> tide::Result<Response> {
     Ok(resp)
 }
 
+fn wrong_password() -> AppErrors {
+    AppErrors::ValidationError(json!({
+        "code": 4,
+        "message": {
+            "cn": "密码错误",
+            "en": "Password is incorrect"
+        }
+    }))
+}
+
+// 开始一个导出任务 已有进行中或仍然有效的任务时直接返回它
+async fn api_export_my_data(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let uid = session.user.to_owned().unwrap();
+    if let Some(job) = ExportJob::latest(&state.db, &uid).await {
+        if job.is_reusable() {
+            return Ok(job.to_response().into());
+        }
+    }
+    // 旧的导出文件不再需要
+    ExportJob::clear_user(&state.db, &uid).await?;
+    let mut job = ExportJob::new(uid);
+    job.save(&state.db, None).await?;
+    let job_id = job.id.clone().unwrap();
+    let db = state.db.clone();
+    let storage = state.config.storage.clone();
+    std::thread::spawn(move || {
+        async_std::task::block_on(crate::exports::run(db, storage, job_id));
+    });
+    Ok(json_response(202, job.to_response()))
+}
+
+async fn api_download_my_export(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    // 只能下载自己的导出文件
+    let job = ExportJob::latest(&state.db, session.user.as_ref().unwrap()).await
+        .filter(|job| job.status == "finished" && job.is_reusable());
+    if let Some(path) = job.and_then(|job| job.local_path) {
+        if let Ok(body) = Body::from_file(&path).await {
+            let mut resp = Response::new(200);
+            resp.insert_header("Content-Disposition", "attachment; filename=\"export.zip\"");
+            resp.insert_header("Cache-Control", "no-store");
+            resp.set_body(body);
+            return Ok(resp);
+        }
+    }
+    Ok(json_response(404, json!({
+        "code": 404,
+        "message": {
+            "cn": "导出文件不存在或已过期",
+            "en": "Export not found or expired"
+        }
+    })))
+}
+
+async fn api_change_password(mut req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let form: PasswordChangeForm = req.body_json().await?;
+    form.validate()?;
+    let state = req.state();
+    let login_config = &state.config.login;
+    let session: &Session = req.ext().unwrap();
+    let mut session = session.to_owned();
+    let uid = session.user.to_owned().unwrap();
+    // 与登录共用失败计数 防止借此猜测密码
+    let user_key = LoginAttempt::user_key(&uid);
+    if let Some(attempt) = LoginAttempt::by_key(&state.db, &user_key).await {
+        if let Some(retry_after) = attempt.retry_after() {
+            return Ok(too_many_attempts(retry_after));
+        }
+    }
+    let mut user = User::by_id(&state.db, &uid).await.unwrap();
+    if !verify_password(&user.password, &form.old_password) {
+        LoginAttempt::record_failure(&state.db, &user_key, login_config.max_failures, login_config).await?;
+        return Err(wrong_password().into());
+    }
+    LoginAttempt::clear(&state.db, &user_key).await?;
+    user.password = hash_password(&form.new_password);
+    user.save(&state.db, None).await?;
+    // 注销其他设备 当前的session换一个新令牌
+    let revoked = Session::revoke_all(&state.db, &uid, session.id.as_ref()).await?;
+    session.rotate();
+    session.save(&state.db, None).await?;
+    info!("用户 {} 修改了密码 注销了 {} 个其他会话", &uid, revoked);
+    let mut resp = Response::new(StatusCode::NoContent);
+    resp.insert_ext(session);
+    Ok(resp)
+}
+
+// 向新邮箱发送验证码 确认前旧邮箱仍然有效
+async fn api_request_email_change(mut req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let form: EmailChangeForm = req.body_json().await?;
+    form.validate()?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let user = User::by_id(&state.db, session.user.as_ref().unwrap()).await.unwrap();
+    if !verify_password(&user.password, &form.password) {
+        return Err(wrong_password().into());
+    }
+    let code = random_code(6);
+    let mut change = EmailChange::new(session.user.to_owned().unwrap(), form.email.clone(), &code, form.lang.clone());
+    EmailChange::replace(&state.db, &mut change).await?;
+    let mail = state.config.email.email_change_letter(&state.config.server,
+                                                      code,
+                                                      user.name,
+                                                      form.lang,
+                                                      form.email);
+    if state.config.email.send(mail).is_err() {
+        change.delete(&state.db).await?;
+        return Ok(json_response(500, json!({
+            "code": 1001,
+            "message": {
+                "cn": "邮件发送失败",
+                "en": "Failed to send email"
+            }
+        })));
+    }
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn api_confirm_email_change(mut req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let form: CodeForm = req.body_json().await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let uid = session.user.to_owned().unwrap();
+    let change = match EmailChange::by_user(&state.db, &uid).await {
+        Some(change) if change.check(&state.db, &form.code).await? => change,
+        _ => return Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "cn": "验证码错误或已过期",
+                "en": "Invalid or expired code"
+            }
+        })).into()),
+    };
+    let mut user = User::by_id(&state.db, &uid).await.unwrap();
+    let old_email = std::mem::replace(&mut user.email, change.new_email.clone());
+    user.save(&state.db, None).await?;
+    change.delete(&state.db).await?;
+    info!("用户 {} 修改了邮箱", &uid);
+    // 通知旧邮箱 发送失败不影响修改结果
+    let mail = state.config.email.notice_letter(
+        ("邮箱已修改", "Email Changed"),
+        ("您账号的邮箱已被修改。", "The email address of your account has been changed."),
+        user.name.clone(),
+        change.lang,
+        old_email);
+    if state.config.email.send(mail).is_err() {
+        warn!("无法通知用户 {} 的旧邮箱", &uid);
+    }
+    Ok(user.to_response(&state.db).await.into())
+}
+
+// 生成新的密钥 需要确认后才会启用
+async fn api_enroll_two_factor(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let mut user = User::by_id(&state.db, session.user.as_ref().unwrap()).await.unwrap();
+    if user.totp_enabled {
+        return Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "cn": "已经启用了两步验证",
+                "en": "Two-factor authentication is already enabled"
+            }
+        })).into());
+    }
+    let secret = crate::totp::generate_secret();
+    user.totp_secret = Some(secret.clone());
+    user.save(&state.db, None).await?;
+    Ok(json!({
+        "secret": &secret,
+        "uri": crate::totp::otpauth_uri(&state.config.two_factor.issuer, &user.name, &secret),
+    }).into())
+}
+
+async fn api_confirm_two_factor(mut req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let form: CodeForm = req.body_json().await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let mut session = session.to_owned();
+    let mut user = User::by_id(&state.db, session.user.as_ref().unwrap()).await.unwrap();
+    let step = match (&user.totp_secret, user.totp_enabled) {
+        (Some(secret), false) => crate::totp::verify(secret, &form.code, None),
+        _ => None,
+    };
+    if step.is_none() {
+        return Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "cn": "两步验证码错误",
+                "en": "Two-factor code is incorrect"
+            }
+        })).into());
+    }
+    let codes = crate::totp::generate_recovery_codes(10);
+    user.totp_enabled = true;
+    user.totp_last_step = step;
+    user.recovery_codes = Some(codes.iter().map(|c| crate::session::hash_token(c)).collect());
+    user.save(&state.db, None).await?;
+    info!("用户 {} 启用了两步验证", user.name);
+    // 当前的session可以获得完整的权限了
+    session.grant(&user, &state.config);
+    session.rotate();
+    session.save(&state.db, None).await?;
+    let mut resp: Response = json!({
+        "recovery_codes": codes,
+    }).into();
+    resp.insert_ext(session);
+    Ok(resp)
+}
+
+async fn api_disable_two_factor(mut req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let form: CodeForm = req.body_json().await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let mut session = session.to_owned();
+    let mut user = User::by_id(&state.db, session.user.as_ref().unwrap()).await.unwrap();
+    if !user.totp_enabled || !user.verify_second_factor(&state.db, &form.code).await? {
+        return Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "cn": "两步验证码错误",
+                "en": "Two-factor code is incorrect"
+            }
+        })).into());
+    }
+    user.totp_enabled = false;
+    user.totp_secret = None;
+    user.totp_last_step = None;
+    user.recovery_codes = None;
+    user.save(&state.db, None).await?;
+    warn!("用户 {} 关闭了两步验证", user.name);
+    session.grant(&user, &state.config);
+    session.save(&state.db, None).await?;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn api_get_my_sessions(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let sessions = Session::by_user(&state.db, session.user.as_ref().unwrap()).await?;
+    let result: Vec<Value> = sessions.iter()
+        .map(|s| s.to_item(s.id == session.id))
+        .collect();
+    Ok(json!(result).into())
+}
+
+async fn api_revoke_my_session(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let id = req.param("id").unwrap().to_owned();
+    // 只能注销属于自己的session
+    if let Some(target) = Session::by_id(&state.db, &id).await {
+        if target.user == session.user {
+            target.delete(&state.db).await?;
+            return Ok(Response::new(204));
+        }
+    }
+    Ok(json_response(404, json!({
+        "code": 4,
+        "message": {
+            "cn": "会话不存在",
+            "en": "Session not found"
+        }
+    })))
+}
+
+// 在所有设备上注销 保留当前的session
+async fn api_revoke_my_sessions(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let revoked = Session::revoke_all(&state.db, session.user.as_ref().unwrap(), session.id.as_ref()).await?;
+    info!("用户 {} 注销了 {} 个其他会话", session.user.as_ref().unwrap(), revoked);
+    Ok(json!({
+        "revoked": revoked
+    }).into())
+}
+
+async fn api_revoke_user_sessions(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::USERS_ADMIN).await?;
+    let state = req.state();
+    let id = req.param("id").unwrap().to_owned();
+    if User::by_id(&state.db, &id).await.is_none() {
+        return Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "cn": "用户不存在",
+                "en": "User does not exist"
+            }
+        })).into());
+    }
+    let revoked = Session::revoke_all(&state.db, &id, None).await?;
+    warn!("管理员注销了用户 {} 的 {} 个会话", &id, revoked);
+    Ok(json!({
+        "revoked": revoked
+    }).into())
+}
+
+// 上传头像 multipart/form-data 中的 file 字段
+async fn api_upload_avatar(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state().to_owned();
+    let session: &Session = req.ext().unwrap();
+    let uid = session.user.to_owned().unwrap();
+    let boundary = match req.content_type() {
+        Some(mime) if mime.essence() == "multipart/form-data" => mime.param("boundary").map(|b| b.to_string()),
+        _ => None,
+    };
+    let boundary = match boundary {
+        Some(boundary) => boundary,
+        None => return Ok(json_response(400, json!({
+            "code": 400,
+            "message": {
+                "cn": "无法识别的请求类型",
+                "en": "Unrecognized request type"
+            },
+            "description": {
+                "cn": "请求类型应该为 multipart/form-data",
+                "en": "Request type should be multipart/form-data"
+            }
+        }))),
+    };
+    let mut body = BufferedBytesStream::new(req);
+    let mut multipart = multer::Multipart::new(&mut body, boundary);
+    let mut data = None;
+    while let Some(mut field) = multipart.next_field().await? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let mut buffer = Vec::new();
+        while let Some(chunk) = field.chunk().await? {
+            if buffer.len() + chunk.len() > avatars::MAX_BYTES {
+                return Err(avatars::too_large().into());
+            }
+            buffer.extend_from_slice(&chunk);
+        }
+        data = Some(buffer);
+    }
+    let data = match data {
+        Some(data) => data,
+        None => return Ok(json_response(400, json!({ "code": 400, "message": {
+            "cn": "请指定文件",
+            "en": "Please specify a file"
+        } }))),
+    };
+    // 不要在异步任务中直接处理图片 以免阻塞其它请求
+    let images = async_std::task::spawn_blocking(move || avatars::render(&data)).await?;
+    let db = &state.db;
+    let mut sizes = Vec::new();
+    for (size, content) in images {
+        let local_path = state.config.storage.get_path(random_filename("png".to_string()));
+        async_std::fs::write(&local_path, content).await?;
+        let mut storage = Storage {
+            id: None,
+            filename: format!("avatar-{}.png", size),
+            local_path,
+            mime_type: "image/png".to_string(),
+            created_at: chrono::Utc::now().into(),
+            owner: uid.clone(),
+        };
+        storage.save(db, None).await?;
+        sizes.push(AvatarSize {
+            size: size as i32,
+            id: storage.id.unwrap().to_hex(),
+        });
+    }
+    let mut user = User::by_id(db, &uid).await.unwrap();
+    let mut previous: Vec<String> = user.avatar_sizes.iter().flatten().map(|s| s.id.to_owned()).collect();
+    previous.extend(user.avatar.to_owned());
+    user.avatar = sizes.first().map(|s| s.id.to_owned());
+    user.avatar_sizes = Some(sizes);
+    user.save(db, None).await?;
+    // 删除自己上传的 不再使用的旧头像
+    for id in previous {
+        if Storage::in_use(db, &id).await {
+            continue;
+        }
+        if let Some(storage) = Storage::by_id(db, &id).await {
+            if storage.owner == uid {
+                storage.remove(db).await?;
+            }
+        }
+    }
+    Ok(user.to_response(db).await.into())
+}
+
+async fn api_get_my_tokens(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let tokens = ApiToken::by_user(&state.db, session.user.as_ref().unwrap()).await?;
+    let result: Vec<Value> = tokens.iter().map(|t| t.to_response()).collect();
+    Ok(json!(result).into())
+}
+
+async fn api_create_token(mut req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let form: NewApiTokenForm = req.body_json().await?;
+    form.validate()?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let expire_at = form.expire_days
+        .map(|days| (chrono::Utc::now() + chrono::Duration::days(days)).into());
+    let (mut token, raw) = ApiToken::generate(session.user.to_owned().unwrap(), form.name, form.scopes, expire_at);
+    token.save(&state.db, None).await?;
+    info!("用户 {} 创建了API令牌 {}", &token.user, &token.hint);
+    // 原始令牌只在这里返回一次
+    let mut result = token.to_response();
+    result["token"] = json!(raw);
+    Ok(json_response(201, result))
+}
+
+async fn api_revoke_token(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let state = req.state();
+    let session: &Session = req.ext().unwrap();
+    let id = req.param("id").unwrap().to_owned();
+    if let Some(token) = ApiToken::by_id(&state.db, &id).await {
+        if Some(&token.user) == session.user.as_ref() {
+            token.delete(&state.db).await?;
+            return Ok(Response::new(204));
+        }
+    }
+    Ok(json_response(404, json!({
+        "code": 4,
+        "message": {
+            "cn": "令牌不存在",
+            "en": "Token not found"
+        }
+    })))
+}
+
 
 pub fn random_string(len: usize) -> String {
     let mut rng = rand::thread_rng();
@@ -219,7 +869,7 @@ pub fn random_string(len: usize) -> String {
 
 // 新注册邀请
 async fn api_new_register_invitation(mut req: Request<AppState>) -> tide::Result<Response> {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.clone();
     let session: &Session = req.ext().unwrap();
@@ -227,6 +877,10 @@ async fn api_new_register_invitation(mut req: Request<AppState>) -> tide::Result
     let session = session.to_owned();
 
     let form: NewInvitationForm = req.body_json().await?;
+    // 只能邀请别人加入自己管理的小组
+    for group_id in form.groups.as_ref().unwrap_or(&vec![]) {
+        require_group_manager(&req, group_id).await?;
+    }
     if form.permission > session.permission {
         return Err(AppErrors::ValidationError(json!({
             "code": 4,
@@ -241,13 +895,12 @@ async fn api_new_register_invitation(mut req: Request<AppState>) -> tide::Result
         chrono::NaiveDateTime::from_timestamp(form.expire_at, 0),
         chrono::Utc,
     );
-    let mut invitation = Invitation {
-        id: None,
-        code: random_string(10),
-        expire_at: expire_at.into(),
-        groups: form.groups,
-        permission: form.permission,
-    };
+    let mut invitation = Invitation::new(random_string(10),
+                                         session.user.as_ref().unwrap(),
+                                         expire_at.into(),
+                                         form.groups,
+                                         form.permission,
+                                         form.max_uses);
     invitation.save(&db, None).await?;
     Ok(json!({
         "code": invitation.code
@@ -271,7 +924,7 @@ async fn api_get_register_invitation(req: Request<AppState>) -> tide::Result<Res
 }
 
 async fn api_get_user(req: Request<AppState>) -> tide::Result<Response> {
-    // require_perm(&req, vec![1, 2, 3]).await?;
+    // require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let id = req.param("id").unwrap();
     if let Some(user) = User::by_id(&state.db, &id.to_string()).await {
@@ -288,14 +941,15 @@ async fn api_get_user(req: Request<AppState>) -> tide::Result<Response> {
 }
 
 async fn api_update_user(mut req: Request<AppState>) -> tide::Result<Response> {
-    require_perm(&req, vec![1, 2, 3]).await?;
+    require_capability(&req, roles::ACCOUNT).await?;
     let state = req.state();
     let db = state.db.clone();
     let session: &Session = req.ext().unwrap();
     let session = session.to_owned();
+    let is_admin = has_capability(state, &session, roles::USERS_ADMIN);
     let id = req.param("id").unwrap().to_owned();
     let form: UpdateUserForm = req.body_json().await?;
-    if session.permission != 3 && session.user.unwrap() != id {
+    if !is_admin && session.user.unwrap() != id {
         // 不是管理员，不能修改其他用户
         return Err(AppErrors::ValidationError(json!({
             "code": 4,
@@ -311,12 +965,17 @@ async fn api_update_user(mut req: Request<AppState>) -> tide::Result<Response> {
             user.name = name;
         }
         if let Some(avatar) = form.avatar {
+            // 直接指定的头像没有其他尺寸
+            if user.avatar.as_ref() != Some(&avatar) {
+                user.avatar_sizes = None;
+            }
             user.avatar = Some(avatar);
         }
-        // 只有管理员可以修改用户权限
+        // 只有管理员可以修改用户权限 修改数字权限时角色随之改变
         if let Some(permission) = form.permission {
-            if session.permission == 3 {
+            if is_admin {
                 user.permission = permission as f64;
+                user.role = Some(roles::role_for_level(permission).to_owned());
             } else {
                 return Err(AppErrors::ValidationError(json!({
                     "code": 4,
@@ -328,6 +987,31 @@ async fn api_update_user(mut req: Request<AppState>) -> tide::Result<Response> {
             }
         }
 
+        if let Some(role) = form.role {
+            if !is_admin {
+                return Err(AppErrors::ValidationError(json!({
+                    "code": 4,
+                    "message": {
+                        "cn": "权限不足",
+                        "en": "Permission denied"
+                    }
+                })).into());
+            }
+            if !req.state().config.roles.exists(&role) {
+                return Err(AppErrors::ValidationError(json!({
+                    "code": 4,
+                    "message": {
+                        "cn": "角色不存在",
+                        "en": "Role does not exist"
+                    },
+                    "description": {
+                        "role": role
+                    }
+                })).into());
+            }
+            user.role = Some(role);
+        }
+
         // 保存更改
         user.save(&db, None).await?;
         Ok(user.to_response(&db).await.into())
@@ -343,12 +1027,17 @@ async fn api_update_user(mut req: Request<AppState>) -> tide::Result<Response> {
 }
 
 async fn api_delete_user(req: Request<AppState>) -> tide::Result<Response> {
-    require_perm(&req, vec![3]).await?;
+    require_capability(&req, roles::USERS_ADMIN).await?;
     let state = req.state();
     let id = req.param("id").unwrap().to_owned();
-    if let Some(user) = User::by_id(&state.db, &id).await {
-        user.delete(&state.db).await?;
-        Ok(user.to_response(&state.db).await.into())
+    let query: DeleteUserQuery = req.query()?;
+    if let Some(mut user) = User::by_id(&state.db, &id).await {
+        let plan = query.to_plan(&state.db, &id).await?;
+        let response = user.to_response(&state.db).await;
+        user.begin_deletion(&state.db, plan).await?;
+        user.finish_deletion(&state.db).await?;
+        warn!("删除了用户 {}", &id);
+        Ok(response.into())
     } else {
         Err(AppErrors::ValidationError(json!({
             "code": 4,
@@ -370,6 +1059,82 @@ fn random_code(length: usize) -> String {
     code
 }
 
+// 无论邮箱是否存在都返回相同的结果 防止枚举邮箱
+async fn api_request_password_reset(mut req: Request<AppState>) -> tide::Result {
+    let ip_key = LoginAttempt::reset_key(&client_ip(&req));
+    let form: PasswordResetForm = req.body_json().await?;
+    let state = req.state();
+    let db = &state.db;
+    let login_config = &state.config.login;
+    // 每个请求都会计数 同一IP请求过多时需要等待
+    if let Some(attempt) = LoginAttempt::by_key(db, &ip_key).await {
+        if let Some(retry_after) = attempt.retry_after() {
+            return Ok(too_many_attempts(retry_after));
+        }
+    }
+    LoginAttempt::record_failure(db, &ip_key, login_config.max_failures_per_ip, login_config).await?;
+    // 允许多个用户使用同一个邮箱 每个用户都会收到自己的验证码
+    for user in User::by_email(db, &form.email).await {
+        let uid = user.id.as_ref().unwrap().to_hex();
+        let code = random_code(6);
+        // 已有的请求只更换验证码 并且需要间隔一段时间才能重新发送
+        // 无论是否发送都返回相同的结果 避免泄露邮箱是否存在
+        let mut reset = match PasswordReset::pending(db, &uid).await? {
+            Some(mut reset) => {
+                if !reset.can_resend() {
+                    continue;
+                }
+                reset.reissue(&code);
+                reset
+            }
+            None => {
+                PasswordReset::clear_user(db, &uid).await?;
+                PasswordReset::new(uid.clone(), form.email.clone(), &code, 30)
+            }
+        };
+        reset.save(db, None).await?;
+        let mail = state.config.email.reset_letter(&state.config.server,
+                                                   code,
+                                                   user.name,
+                                                   form.lang.clone(),
+                                                   form.email.clone());
+        if state.config.email.send(mail).is_err() {
+            warn!("无法向用户 {} 发送重置密码邮件", &uid);
+        }
+    }
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn api_confirm_password_reset(mut req: Request<AppState>) -> tide::Result {
+    let form: PasswordResetConfirmForm = req.body_json().await?;
+    form.validate()?;
+    let state = req.state();
+    let db = &state.db;
+    let reset = PasswordReset::redeem(db, &form.email, &form.code).await?;
+    let user = match reset {
+        Some(reset) => User::by_id(db, &reset.user).await,
+        None => None,
+    };
+    if let Some(mut user) = user {
+        user.password = hash_password(&form.password);
+        user.save(db, None).await?;
+        let uid = user.id.as_ref().unwrap().to_hex();
+        // 密码重置后 所有已登录的设备都需要重新登录
+        let revoked = Session::revoke_all(db, &uid, None).await?;
+        LoginAttempt::clear(db, &LoginAttempt::user_key(&uid)).await?;
+        info!("用户 {} 重置了密码 注销了 {} 个会话", &uid, revoked);
+        Ok(Response::new(StatusCode::NoContent))
+    } else {
+        Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "cn": "验证码错误或已过期",
+                "en": "Invalid or expired code"
+            }
+        })).into())
+    }
+}
+
 async fn api_create_inactive_user(mut req: Request<AppState>) -> tide::Result<Response> {
     let state = req.state().to_owned();
     let db = state.db.clone();
@@ -379,18 +1144,23 @@ async fn api_create_inactive_user(mut req: Request<AppState>) -> tide::Result<Re
     // 尝试获取邀请内容
     if let Some(invitation) = Invitation::by_code(&db, form.invitation).await {
         // 生成验证码
-        let code = random_code(4);
+        let code = random_code(6);
         let mut user = InactiveUser {
             id: None,
             groups: invitation.groups,
             email: form.email.to_owned(),
-            password: form.password,
+            password: hash_password(&form.password),
             permission: invitation.permission,
             name: form.name.to_owned(),
-            code: code.to_owned(),
+            code: String::new(),
             // 验证码过期时间
-            expire_at: (chrono::Utc::now() + chrono::Duration::hours(1)).into(),
+            expire_at: chrono::Utc::now().into(),
+            attempts: 0,
+            sent_at: None,
+            invitation: invitation.id.map(|id| id.to_hex()),
+            claimed: false,
         };
+        user.reissue(&code);
         // 保存到数据库
         user.save(&db, None).await?;
         // 发送邮件
@@ -425,77 +1195,88 @@ async fn api_create_inactive_user(mut req: Request<AppState>) -> tide::Result<Re
     }
 }
 
-async fn api_get_users(req: Request<AppState>) -> tide::Result {
-    require_perm(&req, vec![1, 2, 3]).await?;
-    let state = req.state().to_owned();
-    let db = state.db.clone();
-    let users: Vec<_> = User::find(&db, None, None)
-        .await?
-        .collect()
-        .await;
-    let mut result = Vec::new();
-    for user in users {
-        if let Ok(user) = user {
-            result.push(user.to_response(&db).await);
+// 无论是否存在注册请求都返回相同的结果 防止枚举邮箱
+async fn api_resend_inactive_code(mut req: Request<AppState>) -> tide::Result {
+    let form: ResendCodeForm = req.body_json().await?;
+    let state = req.state();
+    let db = &state.db;
+    for mut user in InactiveUser::pending(db, &form.email).await? {
+        // 限制发送频率
+        if !user.can_resend() {
+            continue;
+        }
+        let code = random_code(6);
+        user.reissue(&code);
+        user.save(db, None).await?;
+        let mail = state.config.email.code_letter(&state.config.server,
+                                                  code,
+                                                  user.name.to_owned(),
+                                                  form.lang.clone(),
+                                                  form.email.clone());
+        if state.config.email.send(mail).is_err() {
+            warn!("无法向 {} 重新发送注册验证码", &form.email);
         }
     }
-    Ok(json!(result).into())
-}
-
-// 事故限定API
-
-#[derive(serde::Deserialize)]
-struct RecoveryForm {
-    name: String,
-    permission: i8,
-    groups: Vec<String>,
-    email: String,
-    password: String,
+    Ok(Response::new(StatusCode::NoContent))
 }
 
-
-async fn api_recovery_user(mut req: Request<AppState>) -> tide::Result {
-    let state = req.state().to_owned();
-    let db = state.db.clone();
-    let form: RecoveryForm = req.body_json().await?;
-    if let Some(..) = User::by_name(&db, &form.name).await {
-        return Ok(json_response(400, json!({
-            "code": 4,
-            "message": {
-                "cn": "用户已存在",
-                "en": "User already exists"
-            }
-        })));
-    }
-    for group in &form.groups {
-        if let None = Group::by_id(&db, group).await {
-            return Ok(json_response(400, json!({
+// 下一页的游标放在 X-Next-Cursor 中 没有下一页时不返回
+async fn api_get_users(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::ACCOUNT).await?;
+    let query: UsersQuery = req.query()?;
+    let state = req.state();
+    let db = &state.db;
+    let (field, direction) = query.sort()?;
+    let limit = query.limit();
+    let mut filter = query.to_filter()?;
+    if let Some(cursor) = &query.cursor {
+        // 从游标指向的用户之后继续
+        let last = match User::by_id(db, cursor).await {
+            Some(last) => last,
+            None => return Err(AppErrors::ValidationError(json!({
                 "code": 4,
                 "message": {
-                    "cn": "调查小组不存在",
-                    "en": "Group does not exist"
+                    "cn": "无效的游标",
+                    "en": "Invalid cursor"
                 }
-            })));
-        }
-    }
-    let mut user = User {
-        id: None,
-        password: form.password,
-        email: form.email,
-        name: form.name,
-        created_at: chrono::Utc::now().into(),
-        groups: Some(form.groups),
-        permission: form.permission as f64,
-        avatar: None,
+            })).into()),
+        };
+        let op = if direction > 0 { "$gt" } else { "$lt" };
+        let last_id = last.id.unwrap();
+        let created_at = *last.created_at;
+        let after = match field {
+            "name" => doc! {"$or": [
+                {"name": {op: &last.name}},
+                {"name": &last.name, "_id": {op: &last_id}},
+            ]},
+            "created_at" => doc! {"$or": [
+                {"created_at": {op: created_at}},
+                {"created_at": created_at, "_id": {op: &last_id}},
+            ]},
+            _ => doc! {"_id": {op: &last_id}},
+        };
+        filter = doc! {"$and": [filter, after]};
+    }
+    let mut opts = FindOptions::default();
+    opts.sort = Some(if field == "_id" {
+        doc! {"_id": direction}
+    } else {
+        doc! {field: direction, "_id": direction}
+    });
+    // 多取一个用于判断是否还有下一页
+    opts.limit = Some(limit + 1);
+    let users: Vec<_> = User::find(db, Some(filter), Some(opts)).await?.collect().await;
+    let mut users: Vec<User> = users.into_iter().flatten().collect();
+    let next = if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        users.last().map(|user| user.id.as_ref().unwrap().to_hex())
+    } else {
+        None
     };
-    user.save(&db, None).await?;
-    let uid = user.id.clone().unwrap().to_hex();
-    if user.permission >= 2.0 {
-        for group_id in user.groups.as_ref().unwrap() {
-            let mut group = Group::by_id(&db, group_id).await.unwrap();
-            group.managers.push(uid.clone());
-            group.save(&db, None).await?;
-        }
+    let result = User::to_responses(db, &users).await?;
+    let mut resp: Response = json!(result).into();
+    if let Some(next) = next {
+        resp.insert_header("X-Next-Cursor", next);
     }
-    Ok(user.to_response(&db).await.into())
-}
\ No newline at end of file
+    Ok(resp)
+}
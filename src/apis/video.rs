@@ -0,0 +1,293 @@
+// 视频检测 对视频按采样率抽帧后逐帧计数
+
+use log::{info, warn};
+use tide::{Request, Response, Server};
+use tide::prelude::*;
+use wither::bson::doc;
+use swift_det_lib::{detect_many, make_env};
+use crate::apis::{json_response, require_capability};
+use crate::AppState;
+use crate::roles;
+use crate::config::Config;
+use crate::models::video_detections::{FrameCount, VideoDetection};
+use crate::models::Session;
+use wither::Model;
+use crate::models::SearchById;
+use futures::StreamExt;
+use wither::bson::oid::ObjectId;
+use wither::mongodb::Database;
+
+pub fn register(app: &mut Server<AppState>) {
+    info!("注册视频检测API");
+    app.at("/detector/video").post(api_create_video_task);
+    app.at("/detector/video/mine").get(api_get_user_video_tasks);
+    app.at("/detector/video/:task_id").get(api_get_video_task)
+        .delete(api_delete_video_task);
+    app.at("/detector/video/:task_id/status").get(api_get_video_task_status);
+}
+
+#[derive(Deserialize)]
+struct CreateVideoTaskForm {
+    attachment: String,
+    model_name: String,
+    overlap: u8,
+    window_size: usize,
+    tile_max_num: u16,
+    // 每秒抽取的帧数
+    sample_rate: f64,
+    threshold: Option<f64>,
+}
+
+// 使用ffmpeg按照采样率抽取视频帧 返回按时间排序的帧文件路径
+fn extract_frames(config: &Config, video_path: &str, output_dir: &str, sample_rate: f64) -> Result<Vec<String>, String> {
+    let status = std::process::Command::new(&config.video.ffmpeg)
+        .arg("-loglevel").arg("error")
+        .arg("-i").arg(video_path)
+        .arg("-vf").arg(format!("fps={}", sample_rate))
+        .arg("-frames:v").arg(config.video.max_frames.to_string())
+        .arg(format!("{}/frame-%06d.png", output_dir))
+        .status()
+        .map_err(|e| format!("无法启动ffmpeg: {}", e))?;
+    if !status.success() {
+        return Err(format!("ffmpeg执行失败: {}", status));
+    }
+    let entries = std::fs::read_dir(output_dir)
+        .map_err(|e| format!("无法读取帧目录: {}", e))?;
+    let mut frames: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().to_string_lossy().to_string())
+        .filter(|path| path.ends_with(".png"))
+        .collect();
+    // 文件名中的序号是补零的 直接排序即可
+    frames.sort();
+    Ok(frames)
+}
+
+async fn mark_failed(db: &Database, task: &VideoDetection) {
+    let task_id = task.id.as_ref().unwrap();
+    if task.to_owned().update(db, None, doc! {
+        "$set": {
+            "status": "failed",
+        },
+        "$unset": {
+            "frames": "",
+            "peak": "",
+            "current": "",
+            "total": "",
+        }
+    }, None).await.is_ok() {
+        warn!("视频任务 {} 失败", task_id);
+    } else {
+        warn!("视频任务 {} 失败 + 更新失败", task_id);
+    }
+}
+
+async fn do_video_task(db: Database, config: Config, task_id: ObjectId, model_path: String) {
+    info!("开始视频检测任务");
+    let mut task = VideoDetection::by_id(&db, &task_id.to_hex()).await.unwrap();
+    let attachment = task.get_attachment(&db).await.unwrap();
+    // 抽出的帧放在存储目录下的临时文件夹中
+    let frames_dir = config.storage.get_path(format!("frames-{}", task_id.to_hex()));
+    if let Err(e) = std::fs::create_dir_all(&frames_dir) {
+        warn!("无法创建帧目录 {}: {}", &frames_dir, e);
+        mark_failed(&db, &task).await;
+        return;
+    }
+    let frames = extract_frames(&config, &attachment.local_path, &frames_dir, task.sample_rate);
+    if let Err(e) = frames {
+        warn!("视频任务 {} 抽帧失败: {}", &task_id, e);
+        let _ = std::fs::remove_dir_all(&frames_dir);
+        mark_failed(&db, &task).await;
+        return;
+    }
+    let frames = frames.unwrap();
+    let total = frames.len();
+    let threshold = task.threshold as f32;
+    let sample_rate = task.sample_rate;
+    let mut counts = Vec::new();
+    let mut failed = false;
+    let env = make_env().unwrap();
+    detect_many(&frames, task.get_config(model_path), env, |index, result| {
+        match result {
+            Ok(boxes) => {
+                counts.push(FrameCount {
+                    time: index as f64 / sample_rate,
+                    num: boxes.iter().filter(|b| b.score >= threshold).count() as i32,
+                });
+                let task = task.to_owned();
+                if async_std::task::block_on(
+                    task.update(&db, None, doc! {
+                        "$set": {
+                            "status": "processing",
+                            "current": (index + 1) as i32,
+                            "total": total as i32,
+                        }
+                    }, None)
+                ).is_err() {
+                    warn!("视频任务 {} 更新失败 进度 {}/{}", &task_id, index + 1, total);
+                }
+                true
+            }
+            Err(e) => {
+                // 一帧失败整个任务就失败了 不再检测剩余的帧
+                warn!("视频任务 {} 第{}帧检测失败: {}", &task_id, index, e);
+                failed = true;
+                false
+            }
+        }
+    }, false);
+    if let Err(e) = std::fs::remove_dir_all(&frames_dir) {
+        warn!("无法删除帧目录 {}: {}", &frames_dir, e);
+    }
+    if failed || counts.is_empty() {
+        mark_failed(&db, &task).await;
+        return;
+    }
+    info!("视频任务 {} 执行完毕", &task_id);
+    task.peak = counts.iter().max_by_key(|frame| frame.num).cloned();
+    task.frames = Some(counts);
+    task.status = "finished".to_owned();
+    task.current = Some(total as isize);
+    task.total = Some(total as isize);
+    task.save(&db, None).await.unwrap();
+}
+
+async fn api_create_video_task(mut req: Request<AppState>) -> tide::Result<Response> {
+    let form: CreateVideoTaskForm = req.body_json().await?;
+
+    let session: &Session = req.ext().unwrap();
+    let state = req.state();
+
+    let model_path = state.config.ai.get_model_path(&form.model_name);
+    // 不存在这个model_name
+    if model_path.is_none() {
+        return Ok(json_response(400, json!({
+            "code": 4,
+            "message": {
+                "cn": "模型不存在",
+                "en": "Model not found",
+            },
+            "description": {
+                "model_name": form.model_name,
+            },
+        })));
+    }
+    let model_path = model_path.unwrap();
+    if !(form.sample_rate > 0. && form.sample_rate <= 30.) {
+        return Ok(json_response(400, json!({
+            "code": 4,
+            "message": {
+                "cn": "采样率必须在0-30之间",
+                "en": "Sample rate must be between 0-30",
+            },
+            "description": {
+                "sample_rate": form.sample_rate,
+            },
+        })));
+    }
+
+    let mut task = VideoDetection {
+        id: None,
+        creator: if let Some(user) = session.user.as_ref() { user.to_owned() } else { "anonymous".to_owned() },
+        created_at: chrono::Utc::now().into(),
+        status: "pending".to_string(),
+        attachment: form.attachment,
+        window_size: form.window_size as isize,
+        overlap: form.overlap as i8,
+        tile_max_num: form.tile_max_num as i16,
+        model_name: form.model_name,
+        sample_rate: form.sample_rate,
+        threshold: form.threshold.unwrap_or(0.5),
+        frames: None,
+        peak: None,
+        current: None,
+        total: None,
+    };
+    if task.get_attachment(&state.db).await.is_none() {
+        return Ok(json_response(400, json!({
+            "code": 4,
+            "message": {
+                "cn": "附件不存在",
+                "en": "Attachment not found",
+            },
+        })));
+    }
+    task.save(&state.db, None).await?;
+    let task_id = task.id.as_ref().unwrap().to_owned();
+
+    let db = state.db.clone();
+    let config = state.config.clone();
+    std::thread::Builder::new()
+        .stack_size(2 * 1024 * 1024 * 1024)
+        .spawn(move || {
+            async_std::task::block_on(do_video_task(db, config, task_id, model_path));
+        })
+        .unwrap();
+    Ok(json!({
+        "task_id": task.id.unwrap().to_hex()
+    }).into())
+}
+
+fn task_not_found() -> Response {
+    json_response(404, json!({
+        "code": 4,
+        "message": {
+            "cn": "任务不存在",
+            "en": "Task not found",
+        },
+    }))
+}
+
+async fn api_get_video_task(req: Request<AppState>) -> tide::Result<Response> {
+    let task_id = req.param("task_id").unwrap();
+    let state = req.state();
+    if let Some(task) = VideoDetection::by_id(&state.db, &task_id.to_string()).await {
+        Ok(task.to_response().into())
+    } else {
+        Ok(task_not_found())
+    }
+}
+
+async fn api_get_video_task_status(req: Request<AppState>) -> tide::Result<Response> {
+    let task_id = req.param("task_id").unwrap();
+    let state = req.state();
+    if let Some(task) = VideoDetection::by_id(&state.db, &task_id.to_string()).await {
+        Ok(json!({
+            "status": task.status,
+            "current": task.current,
+            "total": task.total,
+        }).into())
+    } else {
+        Ok(task_not_found())
+    }
+}
+
+async fn api_delete_video_task(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::DETECTOR_USE).await?;
+    let task_id = req.param("task_id").unwrap().to_owned();
+    let state = req.state();
+    let db = &state.db.to_owned();
+    if let Some(task) = VideoDetection::by_id(db, &task_id).await {
+        task.delete(db).await?;
+        Ok(Response::new(204))
+    } else {
+        Ok(task_not_found())
+    }
+}
+
+async fn api_get_user_video_tasks(req: Request<AppState>) -> tide::Result {
+    require_capability(&req, roles::DETECTOR_USE).await?;
+    let state = req.state();
+    let db = &state.db.to_owned();
+    let session: &Session = req.ext().unwrap();
+    let mut result = Vec::new();
+    let tasks: Vec<_> = VideoDetection::find(db, Some(doc! {
+        "creator": session.user.as_ref().unwrap()
+    }), None).await?.collect().await;
+    for mut task in tasks.into_iter().flatten() {
+        // 列表中不返回逐帧数据
+        task.frames = None;
+        result.push(task.to_response());
+    }
+    Ok(json!(result).into())
+}
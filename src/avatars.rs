@@ -0,0 +1,61 @@
+// 处理上传的头像
+// 检查图片的格式和尺寸 从中间裁剪成正方形并缩放为几种固定的大小
+
+use std::io::Cursor;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat};
+use image::imageops::FilterType;
+use serde_json::json;
+use crate::errors::AppErrors;
+
+// 上传文件的最大字节数
+pub const MAX_BYTES: usize = 5 * 1024 * 1024;
+// 原图的最大边长 防止解码时占用过多内存和时间
+const MAX_DIMENSION: u32 = 4096;
+// 生成的尺寸 第一个作为默认头像
+pub const SIZES: [u32; 3] = [256, 128, 64];
+
+fn invalid(cn: &str, en: &str) -> AppErrors {
+    AppErrors::ValidationError(json!({
+        "code": 4,
+        "message": {
+            "cn": cn,
+            "en": en
+        }
+    }))
+}
+
+pub fn too_large() -> AppErrors {
+    invalid("头像文件不能超过5MB", "Avatar must not exceed 5MB")
+}
+
+fn decode(data: &[u8]) -> Result<DynamicImage, AppErrors> {
+    // 根据文件内容判断格式 不信任文件名和Content-Type
+    let format = image::guess_format(data)
+        .map_err(|_| invalid("无法识别的图片格式", "Unrecognized image format"))?;
+    if !matches!(format, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP) {
+        return Err(invalid("只支持PNG JPEG GIF和WebP格式的头像", "Only PNG, JPEG, GIF and WebP avatars are supported"));
+    }
+    let reader = image::io::Reader::with_format(Cursor::new(data), format);
+    let (width, height) = reader.into_dimensions()
+        .map_err(|_| invalid("无法读取图片", "Can't read image"))?;
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(invalid("图片尺寸不合法", "Invalid image dimensions"));
+    }
+    image::load_from_memory_with_format(data, format)
+        .map_err(|_| invalid("无法读取图片", "Can't read image"))
+}
+
+// 返回每个尺寸对应的PNG数据
+// 解码和缩放比较耗时 需要在单独的线程中调用
+pub fn render(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, AppErrors> {
+    let image = decode(data)?;
+    let mut result = Vec::new();
+    for size in SIZES {
+        let resized = image.resize_to_fill(size, size, FilterType::Lanczos3);
+        let mut buffer = Cursor::new(Vec::new());
+        resized.write_to(&mut buffer, ImageOutputFormat::Png)
+            .map_err(|_| invalid("无法生成头像", "Can't generate avatar"))?;
+        result.push((size, buffer.into_inner()));
+    }
+    Ok(result)
+}
@@ -0,0 +1,136 @@
+// 离线的管理命令 直接操作数据库
+// 用于创建第一个管理员 或者在无法登录时恢复账号
+//
+// swift_backend admin create-user --name <名称> --email <邮箱> --permission <权限> [--groups <id,id>]
+// swift_backend admin restore-user --name <名称> [--permission <权限>]
+// 密码从标准输入读取 不放在命令行参数中 以免被其他用户通过进程列表或shell历史看到
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use wither::Model;
+use wither::mongodb::Database;
+use crate::models::{SearchById, Session};
+use crate::models::groups::Group;
+use crate::models::login_attempts::LoginAttempt;
+use crate::models::memberships::{self, Membership};
+use crate::models::users::User;
+use crate::passwords::{client_digest, hash_password};
+
+const USAGE: &str = "用法:
+  admin create-user --name <名称> --email <邮箱> --permission <0-3> [--groups <id,id>]
+  admin restore-user --name <名称> [--permission <0-3>]
+密码会在执行时从标准输入读取";
+
+// 将 --key value 形式的参数解析为表
+fn parse_flags(args: &[String]) -> Result<HashMap<String, String>, String> {
+    let mut flags = HashMap::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let key = arg.strip_prefix("--").ok_or(format!("无法识别的参数: {}", arg))?;
+        let value = iter.next().ok_or(format!("参数 --{} 缺少值", key))?;
+        flags.insert(key.to_owned(), value.to_owned());
+    }
+    Ok(flags)
+}
+
+fn required<'a>(flags: &'a HashMap<String, String>, key: &str) -> Result<&'a String, String> {
+    flags.get(key).ok_or(format!("缺少参数 --{}", key))
+}
+
+// 从标准输入读取一行密码 也可以通过管道传入
+fn read_password(prompt: &str) -> Result<String, String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush().map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line).map_err(|e| format!("无法读取密码: {}", e))?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+fn parse_permission(value: &str) -> Result<i8, String> {
+    match value.parse::<i8>() {
+        Ok(permission) if (0..=3).contains(&permission) => Ok(permission),
+        _ => Err(format!("无效的权限: {}", value)),
+    }
+}
+
+async fn create_user(db: &Database, flags: HashMap<String, String>) -> Result<(), String> {
+    let name = required(&flags, "name")?;
+    let email = required(&flags, "email")?;
+    let permission = parse_permission(required(&flags, "permission")?)?;
+    let groups: Vec<String> = flags.get("groups")
+        .map(|groups| groups.split(',').map(|g| g.trim().to_owned()).filter(|g| !g.is_empty()).collect())
+        .unwrap_or_default();
+    if User::by_name(db, name).await.is_some() {
+        return Err(format!("用户 {} 已存在", name));
+    }
+    for group_id in &groups {
+        if Group::by_id(db, group_id).await.is_none() {
+            return Err(format!("调查小组 {} 不存在", group_id));
+        }
+    }
+    let password = read_password("密码: ")?;
+    if password.is_empty() {
+        return Err("密码不能为空".to_owned());
+    }
+    let mut user = User {
+        id: None,
+        password: hash_password(&client_digest(&password)),
+        email: email.to_owned(),
+        name: name.to_owned(),
+        created_at: chrono::Utc::now().into(),
+        groups: Some(groups),
+        permission: permission as f64,
+        avatar: None,
+        avatar_sizes: None,
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        recovery_codes: None,
+        deleting: None,
+        role: Some(crate::roles::role_for_level(permission).to_owned()),
+    };
+    user.save(db, None).await.map_err(|e| e.to_string())?;
+    let uid = user.id.as_ref().unwrap().to_hex();
+    let role = if permission >= 2 { memberships::MANAGER } else { memberships::MEMBER };
+    for group_id in user.groups.as_ref().unwrap() {
+        Membership::join(db, &uid, group_id, role).await.map_err(|e| e.to_string())?;
+    }
+    println!("已创建用户 {} ({})", name, uid);
+    Ok(())
+}
+
+async fn restore_user(db: &Database, flags: HashMap<String, String>) -> Result<(), String> {
+    let name = required(&flags, "name")?;
+    let mut user = User::by_name(db, name).await.ok_or(format!("用户 {} 不存在", name))?;
+    // 直接回车时保留原来的密码
+    let password = read_password("新密码 (留空则不修改): ")?;
+    if !password.is_empty() {
+        user.password = hash_password(&client_digest(&password));
+    }
+    if let Some(permission) = flags.get("permission") {
+        let permission = parse_permission(permission)?;
+        user.permission = permission as f64;
+        user.role = Some(crate::roles::role_for_level(permission).to_owned());
+    }
+    user.save(db, None).await.map_err(|e| e.to_string())?;
+    let uid = user.id.as_ref().unwrap().to_hex();
+    // 解除锁定 并注销已有的会话
+    LoginAttempt::clear(db, &LoginAttempt::user_key(&uid)).await.map_err(|e| e.to_string())?;
+    Session::revoke_all(db, &uid, None).await.map_err(|e| e.to_string())?;
+    println!("已恢复用户 {} ({})", name, uid);
+    Ok(())
+}
+
+// 执行一条管理命令 args不包含 "admin" 本身
+pub async fn run(db: &Database, args: &[String]) -> Result<(), String> {
+    let command = args.first().ok_or(USAGE.to_owned())?;
+    let flags = parse_flags(&args[1..])?;
+    if flags.contains_key("password") {
+        return Err("不再支持 --password 参数 请在提示时输入密码".to_owned());
+    }
+    match command.as_str() {
+        "create-user" => create_user(db, flags).await,
+        "restore-user" => restore_user(db, flags).await,
+        _ => Err(USAGE.to_owned()),
+    }
+}
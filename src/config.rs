@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::panic;
 use std::fs::File;
 use std::io::Read;
@@ -126,6 +127,68 @@ const EMAIL_EN: &str = r#"<div class="container">
 </style>"#;
 
 
+// 账号变更的通知邮件
+const NOTICE_CN: &str = r#"<meta charset="utf8">
+<div class="container">
+	<h1>%name%</h1>
+	<div class="content">
+		<p>致 <span class="username">%username%</span>:</p>
+		<p>%content%</p>
+		<center class="tip">如果这不是您本人的操作，请立即联系管理员</center>
+	</div>
+</div>
+<style>
+	.container {
+		text-align: center;
+		max-width: 500px;
+		width: 100%;
+		padding: 5px 30px 30px;
+	}
+	.content {
+		width: 100%;
+		text-align: left;
+	}
+	.username {
+		font-weight: 100;
+		color: #5D5D5D;
+	}
+	.tip {
+		margin-top: 30px;
+		color: #5D5D5D;
+		font-size: 13px;
+	}
+</style>"#;
+
+const NOTICE_EN: &str = r#"<div class="container">
+	<h1>%name%</h1>
+	<div class="content">
+		<p>To <span class="username">%username%</span>:</p>
+		<p>%content%</p>
+		<center class="tip">If this wasn't you, please contact the administrator immediately</center>
+	</div>
+</div>
+<style>
+	.container {
+		text-align: center;
+		max-width: 500px;
+		width: 100%;
+		padding: 5px 30px 30px;
+	}
+	.content {
+		width: 100%;
+		text-align: left;
+	}
+	.username {
+		font-weight: 100;
+		color: #5D5D5D;
+	}
+	.tip {
+		margin-top: 30px;
+		color: #5D5D5D;
+		font-size: 13px;
+	}
+</style>"#;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct DatabaseConfig {
     pub path: String,
@@ -146,6 +209,22 @@ impl StorageConfig {
 pub struct SessionConfig {
     pub logout_on_ip_change: bool,
     pub timeout: u64,
+    // cookie的属性
+    #[serde(default)]
+    pub cookie_secure: bool,
+    #[serde(default = "default_cookie_http_only")]
+    pub cookie_http_only: bool,
+    // strict / lax / none
+    #[serde(default = "default_cookie_same_site")]
+    pub cookie_same_site: String,
+}
+
+fn default_cookie_http_only() -> bool {
+    true
+}
+
+fn default_cookie_same_site() -> String {
+    "lax".to_string()
 }
 
 
@@ -178,32 +257,53 @@ impl EmailConfig {
     }
     // 制作一个验证码邮件
     pub fn code_letter(&self, config: &ServerConfig, code: String, name: String, lang: String, to_email: String) -> Email {
-        if lang == "cn" {
-            let body = EMAIL_CN.replace("%url%", &format!("{}/code/{}", config.base_url, code))
-                .replace("%code%", &code.to_string())
-                .replace("%name%", NAME_CN)
-                .replace("%username%", &name)
-                .replace("%expire%", "60");
-
-            EmailBuilder::new()
-                .to((to_email, name))
-                .from((self.from.as_str(), format!("[{}]", NAME_CN)))
-                .subject(format!("[{}] 验证码", NAME_CN))
-                .html(body)
-                .build().unwrap()
+        self.make_code_letter(config, "code", ("验证码", "Verification Code"), 60, code, name, lang, to_email)
+    }
+    // 制作一个重置密码的邮件
+    pub fn reset_letter(&self, config: &ServerConfig, code: String, name: String, lang: String, to_email: String) -> Email {
+        self.make_code_letter(config, "reset_password", ("重置密码", "Password Reset"), 30, code, name, lang, to_email)
+    }
+    // 制作一个修改邮箱的验证码邮件 发往新的邮箱
+    pub fn email_change_letter(&self, config: &ServerConfig, code: String, name: String, lang: String, to_email: String) -> Email {
+        self.make_code_letter(config, "change_email", ("验证新邮箱", "Verify New Email"), 60, code, name, lang, to_email)
+    }
+    // 制作一个通知邮件 content为(中文, 英文)
+    pub fn notice_letter(&self, subject: (&str, &str), content: (&str, &str), name: String, lang: String, to_email: String) -> Email {
+        let (template, site_name, subject, content) = if lang == "cn" {
+            (NOTICE_CN, NAME_CN, subject.0, content.0)
         } else {
-            let body = EMAIL_EN.replace("%url%", &format!("{}/code/{}", config.base_url, code))
-                .replace("%code%", &code.to_string())
-                .replace("%name%", NAME_EN)
-                .replace("%username%", &name)
-                .replace("%expire%", "60");
-            EmailBuilder::new()
-                .to((to_email, name))
-                .from((self.from.as_str(), format!("[{}]", NAME_EN)))
-                .subject(format!("[{}] Verification Code", NAME_EN))
-                .html(body)
-                .build().unwrap()
-        }
+            (NOTICE_EN, NAME_EN, subject.1, content.1)
+        };
+        let body = template.replace("%name%", site_name)
+            .replace("%username%", &name)
+            .replace("%content%", content);
+        EmailBuilder::new()
+            .to((to_email, name))
+            .from((self.from.as_str(), format!("[{}]", site_name)))
+            .subject(format!("[{}] {}", site_name, subject))
+            .html(body)
+            .build().unwrap()
+    }
+    // 各种验证码邮件共用的模板 path是前端应用验证码的页面
+    #[allow(clippy::too_many_arguments)]
+    fn make_code_letter(&self, config: &ServerConfig, path: &str, subject: (&str, &str), expire: i64,
+                        code: String, name: String, lang: String, to_email: String) -> Email {
+        let (template, site_name, subject) = if lang == "cn" {
+            (EMAIL_CN, NAME_CN, subject.0)
+        } else {
+            (EMAIL_EN, NAME_EN, subject.1)
+        };
+        let body = template.replace("%url%", &format!("{}/{}/{}", config.base_url, path, code))
+            .replace("%code%", &code)
+            .replace("%name%", site_name)
+            .replace("%username%", &name)
+            .replace("%expire%", &expire.to_string());
+        EmailBuilder::new()
+            .to((to_email, name))
+            .from((self.from.as_str(), format!("[{}]", site_name)))
+            .subject(format!("[{}] {}", site_name, subject))
+            .html(body)
+            .build().unwrap()
     }
 }
 
@@ -211,6 +311,8 @@ impl EmailConfig {
 pub struct Model {
     pub name: String,
     pub path: String,
+    // 模型版本 更换同名模型时应修改
+    pub version: Option<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -219,6 +321,9 @@ pub struct AiConfig {
 }
 
 impl AiConfig {
+    pub fn get_model(&self, name: &str) -> Option<&Model> {
+        self.models.iter().find(|model| model.name == name)
+    }
     pub fn get_model_path(&self, name: &str) -> Option<String> {
         for model in &self.models {
             if model.name == name {
@@ -229,12 +334,140 @@ impl AiConfig {
     }
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct VideoConfig {
+    // ffmpeg可执行文件的路径
+    pub ffmpeg: String,
+    // 单个视频最多抽取的帧数
+    pub max_frames: u32,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            ffmpeg: "ffmpeg".to_string(),
+            max_frames: 600,
+        }
+    }
+}
+
+// 登录失败的退避和锁定策略
+#[derive(Deserialize, Debug, Clone)]
+pub struct LoginConfig {
+    // 同一账号连续失败多少次后锁定
+    pub max_failures: i32,
+    // 同一IP连续失败多少次后锁定
+    pub max_failures_per_ip: i32,
+    // 锁定时长 单位为秒 超过这个时间没有失败则重新计数
+    pub lockout: i64,
+    // 第n次失败后需等待 backoff_base * 2^(n-1) 秒
+    pub backoff_base: i64,
+    pub backoff_max: i64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        LoginConfig {
+            max_failures: 5,
+            max_failures_per_ip: 20,
+            lockout: 900,
+            backoff_base: 1,
+            backoff_max: 60,
+        }
+    }
+}
+
+// 检测任务的保留策略
+#[derive(Deserialize, Debug, Clone)]
+pub struct RetentionConfig {
+    pub enabled: bool,
+    // 两次清理之间的间隔 单位为秒
+    pub interval: u64,
+    // 各类任务的保留天数
+    pub finished_days: i64,
+    pub failed_days: i64,
+    pub anonymous_days: i64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            enabled: false,
+            interval: 3600,
+            finished_days: 180,
+            failed_days: 30,
+            anonymous_days: 7,
+        }
+    }
+}
+
+// 两步验证
+#[derive(Deserialize, Debug, Clone)]
+pub struct TwoFactorConfig {
+    // 验证器应用中显示的名称
+    pub issuer: String,
+    // 权限不低于此值 或者角色拥有此权限对应角色以下没有的能力的用户必须启用两步验证
+    // 未启用时只能获得普通用户的权限
+    pub required_permission: Option<i8>,
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig {
+            issuer: NAME_EN.to_string(),
+            required_permission: None,
+        }
+    }
+}
+
+impl TwoFactorConfig {
+    // 是否必须启用两步验证 角色可以单独修改 所以同时检查角色的能力
+    pub fn is_required(&self, roles: &RolesConfig, permission: i8, role: &str) -> bool {
+        match self.required_permission {
+            Some(required) => permission >= required
+                || roles.exceeds(role, crate::roles::role_for_level(required - 1)),
+            None => false,
+        }
+    }
+}
+
+// 角色名称到能力列表的映射
+#[derive(Deserialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct RolesConfig {
+    pub roles: HashMap<String, Vec<String>>,
+}
+
+impl Default for RolesConfig {
+    fn default() -> Self {
+        RolesConfig {
+            roles: crate::roles::default_roles(),
+        }
+    }
+}
+
+impl RolesConfig {
+    pub fn exists(&self, role: &str) -> bool {
+        self.roles.contains_key(role)
+    }
+    pub fn allows(&self, role: &str, capability: &str) -> bool {
+        self.roles.get(role).is_some_and(|capabilities| capabilities.iter().any(|c| c == capability))
+    }
+    // role是否拥有base没有的能力
+    pub fn exceeds(&self, role: &str, base: &str) -> bool {
+        self.roles.get(role).is_some_and(|capabilities| capabilities.iter().any(|c| !self.allows(base, c)))
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub origins: Vec<String>,
     pub base_url: String,
+    // 部署在反向代理之后时开启 此时才信任 Forwarded 和 X-Forwarded-For 中的客户端地址
+    #[serde(default)]
+    pub trusted_proxy: bool,
 }
 
 impl ServerConfig {
@@ -251,6 +484,16 @@ pub struct Config {
     pub email: EmailConfig,
     pub ai: AiConfig,
     pub server: ServerConfig,
+    #[serde(default)]
+    pub video: VideoConfig,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    #[serde(default)]
+    pub login: LoginConfig,
+    #[serde(default)]
+    pub two_factor: TwoFactorConfig,
+    #[serde(default)]
+    pub roles: RolesConfig,
 }
 
 fn _load_config() -> Config {
@@ -6,12 +6,14 @@ use tide::prelude::*;
 
 #[derive(Debug, Clone)]
 pub enum AppErrors {
-    CrossPermissionError(i8, Vec<i8>),
-    // 越权访问
     // BadRequest,
     // // 请求错误
     ValidationError(serde_json::Value),
     // 验证错误
+    MissingCapability(String),
+    // 当前角色缺少某个能力
+    NotGroupManager(String),
+    // 不是某个小组的管理员
 }
 
 
@@ -47,8 +49,8 @@ pub async fn handle(mut res: Response) -> tide::Result<Response> {
 
         let err = err.to_owned();
         match err {
-            // 越权 check perm的时候触发
-            AppErrors::CrossPermissionError(current, allowed) => {
+            // check capability的时候触发
+            AppErrors::MissingCapability(capability) => {
                 res.set_body(json!({
                     "code": 1,
                     "message": {
@@ -56,8 +58,22 @@ pub async fn handle(mut res: Response) -> tide::Result<Response> {
                         "en": "Cross permission"
                     },
                     "description": {
-                        "current": current,
-                        "allowed": allowed
+                        "required": capability
+                    }
+                }));
+                res.set_status(StatusCode::Forbidden);
+                res.set_content_type("application/json");
+            }
+            // check group manager的时候触发
+            AppErrors::NotGroupManager(group) => {
+                res.set_body(json!({
+                    "code": 1,
+                    "message": {
+                        "cn": "您不是该小组的管理员",
+                        "en": "You are not a manager of this group"
+                    },
+                    "description": {
+                        "group": group
                     }
                 }));
                 res.set_status(StatusCode::Forbidden);
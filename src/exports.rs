@@ -0,0 +1,124 @@
+// 生成用户数据的导出文件
+// 包括个人资料 记录 检测任务 草稿 以及上传的文件
+
+use std::fs::File;
+use std::io::Write;
+use futures::StreamExt;
+use log::{info, warn};
+use serde_json::{json, Value};
+use wither::bson::doc;
+use wither::bson::oid::ObjectId;
+use wither::Model;
+use wither::mongodb::Database;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+use crate::config::StorageConfig;
+use crate::models::SearchById;
+use crate::models::detections::Detection;
+use crate::models::drafts::RecordDraft;
+use crate::models::export_jobs::ExportJob;
+use crate::models::records::Record;
+use crate::models::storage::Storage;
+use crate::models::users::User;
+use crate::models::video_detections::VideoDetection;
+
+// 导出的全部内容 文件只保存路径 写入ZIP时再读取
+struct Export {
+    documents: Vec<(&'static str, Value)>,
+    files: Vec<Storage>,
+}
+
+async fn collect(db: &Database, uid: &str) -> Result<Export, String> {
+    let user = User::by_id(db, &uid.to_owned()).await.ok_or("用户不存在")?;
+    let mut documents = vec![("profile.json", user.to_response(db).await)];
+
+    let records: Vec<_> = Record::find(db, Some(doc! {
+        "$or": [{"user": uid}, {"collaborators": uid}]
+    }), None).await.map_err(|e| e.to_string())?.collect().await;
+    let records: Vec<Value> = records.into_iter().flatten().map(|r| r.to_response()).collect();
+    documents.push(("records.json", json!(records)));
+
+    let detections: Vec<_> = Detection::find(db, Some(doc! {"creator": uid}), None)
+        .await.map_err(|e| e.to_string())?.collect().await;
+    let mut tasks = Vec::new();
+    for task in detections.into_iter().flatten() {
+        tasks.push(json!(task.to_info().await));
+    }
+    documents.push(("detections.json", json!(tasks)));
+
+    let videos: Vec<_> = VideoDetection::find(db, Some(doc! {"creator": uid}), None)
+        .await.map_err(|e| e.to_string())?.collect().await;
+    let videos: Vec<Value> = videos.into_iter().flatten().map(|v| v.to_response()).collect();
+    documents.push(("video_detections.json", json!(videos)));
+
+    let drafts: Vec<_> = RecordDraft::find(db, Some(doc! {"user": uid}), None)
+        .await.map_err(|e| e.to_string())?.collect().await;
+    let drafts: Vec<Value> = drafts.into_iter().flatten().map(|d| d.to_response()).collect();
+    documents.push(("drafts.json", json!(drafts)));
+
+    let files: Vec<_> = Storage::find(db, Some(doc! {"owner": uid}), None)
+        .await.map_err(|e| e.to_string())?.collect().await;
+    let files: Vec<Storage> = files.into_iter().flatten().collect();
+    let index: Vec<Value> = files.iter().map(|f| f.to_owned().to_response()).collect();
+    documents.push(("files.json", json!(index)));
+
+    Ok(Export { documents, files })
+}
+
+fn write_zip(path: &str, export: Export) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("无法创建导出文件: {}", e))?;
+    let mut zip = ZipWriter::new(file);
+    let options = FileOptions::default();
+    for (name, document) in export.documents {
+        zip.start_file(name, options).map_err(|e| e.to_string())?;
+        let content = serde_json::to_vec_pretty(&document).map_err(|e| e.to_string())?;
+        zip.write_all(&content).map_err(|e| e.to_string())?;
+    }
+    for storage in export.files {
+        // 文件名前加上ID 避免重名
+        let name = format!("files/{}-{}", storage.id.as_ref().unwrap().to_hex(), storage.filename);
+        match std::fs::read(&storage.local_path) {
+            Ok(content) => {
+                zip.start_file(name, options).map_err(|e| e.to_string())?;
+                zip.write_all(&content).map_err(|e| e.to_string())?;
+            }
+            Err(e) => warn!("导出时无法读取文件 {}: {}", &storage.local_path, e),
+        }
+    }
+    zip.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+pub async fn run(db: Database, storage: StorageConfig, job_id: ObjectId) {
+    let mut job = match ExportJob::by_id(&db, &job_id.to_hex()).await {
+        Some(job) => job,
+        None => return,
+    };
+    info!("开始导出用户 {} 的数据", &job.user);
+    job.status = "processing".to_string();
+    if let Err(e) = job.save(&db, None).await {
+        warn!("无法更新导出任务 {}: {}", &job_id, e);
+        return;
+    }
+    let path = storage.get_path(format!("export-{}.zip", job_id.to_hex()));
+    let result = match collect(&db, &job.user).await {
+        Ok(export) => write_zip(&path, export),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(..) => {
+            job.status = "finished".to_string();
+            job.local_path = Some(path);
+        }
+        Err(e) => {
+            warn!("导出任务 {} 失败: {}", &job_id, e);
+            let _ = std::fs::remove_file(&path);
+            job.status = "failed".to_string();
+            job.error = Some(e);
+        }
+    }
+    job.finished_at = Some(chrono::Utc::now().into());
+    if let Err(e) = job.save(&db, None).await {
+        warn!("无法更新导出任务 {}: {}", &job_id, e);
+    }
+}
@@ -2,6 +2,7 @@ use serde_json::json;
 use wither::bson::doc;
 use wither::mongodb::Database;
 use crate::errors::AppErrors;
+use crate::forms::check_max_uses;
 use crate::forms::positions::UpdatePositionForm;
 use crate::models::SearchById;
 use crate::models::users::User;
@@ -116,6 +117,8 @@ pub struct JoinInvitationForm {
     pub expire_at: i64,
     pub groups: Vec<String>,
     pub permission: i8,
+    // 默认只能使用一次
+    pub max_uses: Option<i32>,
 }
 
 impl JoinInvitationForm {
@@ -145,6 +148,7 @@ impl JoinInvitationForm {
                 }
             })));
         }
+        check_max_uses(self.max_uses)?;
         Ok(())
     }
 }
\ No newline at end of file
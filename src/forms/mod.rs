@@ -8,6 +8,20 @@ pub mod groups;
 pub mod projects;
 pub mod records;
 
+// 邀请的使用次数 为空时不限制
+pub fn check_max_uses(max_uses: Option<i32>) -> Result<(), AppErrors> {
+    match max_uses {
+        Some(max_uses) if max_uses < 1 => Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "en": "Max uses must be at least 1",
+                "cn": "使用次数至少为1"
+            }
+        }))),
+        _ => Ok(()),
+    }
+}
+
 pub fn try_into_object_id(id: String) -> Result<ObjectId, AppErrors> {
     match ObjectId::with_string(&id) {
         Ok(oid) => Ok(oid),
@@ -1,12 +1,15 @@
 use serde_json::json;
 use tide::Response;
-use wither::bson::doc;
+use wither::bson::{doc, Document};
 use wither::mongodb::Database;
 use serde::Deserialize;
 use crate::errors::AppErrors;
+use crate::forms::check_max_uses;
 use crate::models::SearchById;
-use crate::models::users::User;
+use crate::models::users::{DeletionPlan, User};
 use crate::models::groups::Group;
+use crate::passwords::hash_password;
+use crate::models::api_tokens::SCOPES;
 
 // 管理员直接创建新用户时的Form
 #[derive(Deserialize)]
@@ -186,12 +189,19 @@ impl NewUserForm {
         User {
             id: None,
             name: self.name,
-            password: self.password,
+            password: hash_password(&self.password),
             email: self.email,
             permission: self.permission as f64,
             groups: self.groups,
             created_at: chrono::Utc::now().into(),
             avatar: None,
+            avatar_sizes: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+            recovery_codes: None,
+            deleting: None,
+            role: Some(crate::roles::role_for_level(self.permission).to_owned()),
         }
     }
 }
@@ -199,64 +209,63 @@ impl NewUserForm {
 // 激活一个InactiveUser
 #[derive(Debug, Deserialize)]
 pub struct NewUserFromInactive {
-    // pub name: String,
-    // pub password: String,
+    // 注册时填写的邮箱 用于限制同一个验证码的尝试次数
+    pub email: String,
     pub code: String, // 验证码
 }
 
-impl NewUserFromInactive {
-    pub async fn validate(&self, db: &Database) -> Result<(), AppErrors> {
-        // // 首先执行非联网验证
-        // register_check_name(self.name.clone(), db).await?;
-        // check_password(self.password.clone())?;
-
-        // 检查InactiveUser是否存在
-        let codes = db.collection("inactive_users");
-        let code = codes.find_one(Some(doc! {
-            "code": self.code.clone()
-        }), None).await.unwrap_or(None);
-        if code.is_none() {
-            return Err(AppErrors::ValidationError(json!({
-                "code": 4,
-                "message": {
-                    "cn": "验证码不存在",
-                    "en": "Verification code does not exist"
-                },
-                "description": {
-                    "code": self.code.clone()
-                }
-            })));
-        }
-
-        Ok(())
-    }
+// 重新发送注册验证码的Form
+#[derive(Deserialize)]
+pub struct ResendCodeForm {
+    pub email: String,
+    pub lang: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct LoginForm {
-    pub id: String,
+    // 用户的ID 邮箱或名称
+    pub identifier: Option<String>,
+    // 旧版客户端使用uid登录
+    pub id: Option<String>,
     pub password: String,
+    // 启用了两步验证的用户需要提供验证码或恢复码
+    pub totp: Option<String>,
 }
 
 impl LoginForm {
-    pub async fn validate(&self, db: &Database) -> Result<(), AppErrors> {
-        // 首先执行非联网验证
-        if User::by_id(&db, &self.id).await.is_none() {
-            // 登录逻辑改为了用uid登录
+    fn get_identifier(&self) -> Option<&String> {
+        self.identifier.as_ref().or(self.id.as_ref())
+    }
+    pub fn validate(&self) -> Result<(), AppErrors> {
+        // 只做离线验证 用户是否存在不在这里判断 避免泄露账号信息
+        if self.get_identifier().is_none_or(|identifier| identifier.is_empty()) {
             return Err(AppErrors::ValidationError(json!({
                 "code": 4,
                 "message": {
-                    "cn": "用户不存在",
-                    "en": "User does not exist"
-                },
-                "description": {
-                    "id": self.id
+                    "cn": "请输入账号",
+                    "en": "Identifier is required"
                 }
             })));
         }
         check_password(self.password.clone())?;
         Ok(())
     }
+    // 找出所有可能对应的用户 依次按ID 邮箱 名称解析
+    pub async fn candidates(&self, db: &Database) -> Vec<User> {
+        let identifier = match self.get_identifier() {
+            Some(identifier) => identifier.trim().to_owned(),
+            None => return Vec::new(),
+        };
+        let users = if let Some(user) = User::by_id(db, &identifier).await {
+            vec![user]
+        } else if check_email(identifier.clone()) {
+            User::by_email(db, &identifier).await
+        } else {
+            User::by_name(db, &identifier).await.into_iter().collect()
+        };
+        // 正在删除的用户不能登录
+        users.into_iter().filter(|user| user.deleting.is_none()).collect()
+    }
 }
 
 
@@ -265,21 +274,128 @@ pub struct NewInvitationForm {
     pub groups: Option<Vec<String>>,
     pub expire_at: i64,
     pub permission: i8,
+    // 为空时不限制使用次数
+    pub max_uses: Option<i32>,
 }
 
 impl NewInvitationForm {
     pub async fn validate(&self, db: &Database) -> Result<(), AppErrors> {
         // 检查小组是否存在
         check_groups(self.groups.as_ref().unwrap_or(&vec![]), db).await?;
+        check_max_uses(self.max_uses)?;
         Ok(())
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct InvitationsQuery {
+    // 列出某个小组的邀请 需要是小组的管理员
+    pub group: Option<String>,
+    // 列出某个用户创建的邀请 默认为当前用户
+    pub creator: Option<String>,
+}
+
+// 每页默认和最多返回的用户数
+pub const USERS_PAGE_SIZE: i64 = 100;
+pub const USERS_PAGE_MAX: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct UsersQuery {
+    // 名称或邮箱中包含的内容
+    pub search: Option<String>,
+    // 小组id
+    pub group: Option<String>,
+    pub permission: Option<i8>,
+    // 注册时间的范围
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    // name created_at 前面加上 - 表示降序
+    pub sort: Option<String>,
+    // 上一页最后一个用户的id
+    pub cursor: Option<String>,
+    pub limit: Option<i64>,
+}
+
+// 查询参数中的时间戳 超出范围时返回错误而不是panic
+fn query_time(field: &str, timestamp: i64) -> Result<chrono::DateTime<chrono::Utc>, AppErrors> {
+    match chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0) {
+        Some(time) => Ok(chrono::DateTime::<chrono::Utc>::from_utc(time, chrono::Utc)),
+        None => Err(AppErrors::ValidationError(json!({
+            "code": 4,
+            "message": {
+                "cn": "时间超出范围",
+                "en": "Timestamp out of range"
+            },
+            "description": {
+                field: timestamp
+            }
+        }))),
+    }
+}
+
+impl UsersQuery {
+    pub fn to_filter(&self) -> Result<Document, AppErrors> {
+        let mut filter = doc! {};
+        if let Some(search) = &self.search {
+            let pattern = regex::escape(search.trim());
+            filter.insert("$or", vec![
+                doc! {"name": {"$regex": &pattern, "$options": "i"}},
+                doc! {"email": {"$regex": &pattern, "$options": "i"}},
+            ]);
+        }
+        if let Some(group) = &self.group {
+            filter.insert("groups", group);
+        }
+        if let Some(permission) = self.permission {
+            filter.insert("permission", permission as f64);
+        }
+        let mut created_at = doc! {};
+        if let Some(from) = self.from {
+            created_at.insert("$gte", query_time("from", from)?);
+        }
+        if let Some(to) = self.to {
+            created_at.insert("$lte", query_time("to", to)?);
+        }
+        if !created_at.is_empty() {
+            filter.insert("created_at", created_at);
+        }
+        Ok(filter)
+    }
+    // 排序的字段和方向 默认按照id升序 也就是注册的顺序
+    pub fn sort(&self) -> Result<(&'static str, i32), AppErrors> {
+        let sort = self.sort.as_deref().unwrap_or("_id");
+        let (field, direction) = match sort.strip_prefix('-') {
+            Some(field) => (field, -1),
+            None => (sort, 1),
+        };
+        match field {
+            "_id" | "id" => Ok(("_id", direction)),
+            "name" => Ok(("name", direction)),
+            "created_at" => Ok(("created_at", direction)),
+            _ => Err(AppErrors::ValidationError(json!({
+                "code": 4,
+                "message": {
+                    "cn": "不支持的排序方式",
+                    "en": "Unsupported sort field"
+                },
+                "description": {
+                    "sort": sort
+                }
+            }))),
+        }
+    }
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(USERS_PAGE_SIZE).clamp(1, USERS_PAGE_MAX)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UpdateUserForm {
     pub name: Option<String>,
     pub avatar: Option<String>,
     pub permission: Option<i8>,
+    // 角色 只有管理员可以修改
+    pub role: Option<String>,
 }
 
 impl UpdateUserForm {
@@ -362,4 +478,167 @@ impl CreateInactiveUserForm {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// 创建API令牌的Form
+#[derive(Deserialize)]
+pub struct NewApiTokenForm {
+    pub name: String,
+    pub scopes: Vec<String>,
+    // 有效天数 不填则永不过期
+    pub expire_days: Option<i64>,
+}
+
+impl NewApiTokenForm {
+    pub fn validate(&self) -> Result<(), AppErrors> {
+        if self.name.is_empty() || self.name.len() > 64 {
+            return Err(AppErrors::ValidationError(json!({
+                "code": 4,
+                "message": {
+                    "cn": "令牌名称长度必须在1-64个字符之间",
+                    "en": "Token name length must be between 1-64 characters"
+                }
+            })));
+        }
+        if self.scopes.is_empty() || self.scopes.iter().any(|s| !SCOPES.contains(&s.as_str())) {
+            return Err(AppErrors::ValidationError(json!({
+                "code": 4,
+                "message": {
+                    "cn": "无效的权限范围",
+                    "en": "Invalid scopes"
+                },
+                "description": {
+                    "allowed": SCOPES
+                }
+            })));
+        }
+        if let Some(days) = self.expire_days {
+            if days <= 0 {
+                return Err(AppErrors::ValidationError(json!({
+                    "code": 4,
+                    "message": {
+                        "cn": "有效天数必须大于0",
+                        "en": "Expire days must be greater than 0"
+                    }
+                })));
+            }
+        }
+        Ok(())
+    }
+}
+
+// 申请重置密码的Form
+#[derive(Deserialize)]
+pub struct PasswordResetForm {
+    pub email: String,
+    pub lang: String,
+}
+
+// 使用验证码重置密码的Form
+#[derive(Deserialize)]
+pub struct PasswordResetConfirmForm {
+    pub email: String,
+    pub code: String,
+    pub password: String,
+}
+
+impl PasswordResetConfirmForm {
+    pub fn validate(&self) -> Result<(), AppErrors> {
+        check_password(self.password.clone())
+    }
+}
+
+// 只包含一个验证码的Form 用于两步验证和修改邮箱等
+#[derive(Deserialize)]
+pub struct CodeForm {
+    pub code: String,
+}
+
+// 申请修改邮箱的Form
+#[derive(Deserialize)]
+pub struct EmailChangeForm {
+    pub email: String,
+    // 当前的密码
+    pub password: String,
+    pub lang: String,
+}
+
+impl EmailChangeForm {
+    pub fn validate(&self) -> Result<(), AppErrors> {
+        // 与自助注册相同 允许多个用户使用同一个邮箱 只检查格式
+        if !check_email(self.email.clone()) {
+            return Err(AppErrors::ValidationError(json!({
+                "code": 4,
+                "message": {
+                    "cn": "邮箱格式不正确",
+                    "en": "Email format is incorrect"
+                },
+                "description": {
+                    "email": self.email.clone()
+                }
+            })));
+        }
+        Ok(())
+    }
+}
+
+// 已登录用户修改密码的Form
+#[derive(Deserialize)]
+pub struct PasswordChangeForm {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+impl PasswordChangeForm {
+    pub fn validate(&self) -> Result<(), AppErrors> {
+        check_password(self.new_password.clone())
+    }
+}
+
+// 删除用户时的参数
+#[derive(Deserialize)]
+pub struct DeleteUserQuery {
+    // reassign 或 anonymize 默认为 anonymize
+    pub mode: Option<String>,
+    // 转交数据的目标用户
+    pub to: Option<String>,
+}
+
+impl DeleteUserQuery {
+    pub async fn to_plan(&self, db: &Database, user_id: &str) -> Result<DeletionPlan, AppErrors> {
+        match self.mode.as_deref().unwrap_or("anonymize") {
+            "anonymize" => Ok(DeletionPlan::Anonymize),
+            "reassign" => {
+                let target = match &self.to {
+                    Some(to) if to != user_id => User::by_id(db, to).await,
+                    _ => None,
+                };
+                match target {
+                    Some(target) if target.deleting.is_none() => Ok(DeletionPlan::Reassign {
+                        to: target.id.unwrap().to_hex()
+                    }),
+                    _ => Err(AppErrors::ValidationError(json!({
+                        "code": 4,
+                        "message": {
+                            "cn": "转交的目标用户不存在",
+                            "en": "Target user does not exist"
+                        },
+                        "description": {
+                            "to": self.to
+                        }
+                    }))),
+                }
+            }
+            _ => Err(AppErrors::ValidationError(json!({
+                "code": 4,
+                "message": {
+                    "cn": "无效的删除方式",
+                    "en": "Invalid deletion mode"
+                },
+                "description": {
+                    "allowed": ["reassign", "anonymize"]
+                }
+            }))),
+        }
+    }
+}
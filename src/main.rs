@@ -4,6 +4,13 @@ mod models;
 mod session;
 mod errors;
 mod forms;
+mod passwords;
+mod sweeper;
+mod cli;
+mod totp;
+mod exports;
+mod roles;
+mod avatars;
 
 use log::{error, info};
 use tide::http::headers::HeaderValue;
@@ -36,6 +43,39 @@ async fn main() {
     let db = db.unwrap();
     let db = db.database("swiftnext");
     info!("数据库连接成功");
+    if let Err(e) = models::sync_indexes(&db).await {
+        error!("无法同步数据库索引: {}", e);
+    }
+    if let Err(e) = roles::migrate_users(&db).await {
+        error!("无法为用户分配角色: {}", e);
+    }
+    if let Err(e) = models::memberships::Membership::migrate(&db).await {
+        error!("无法创建小组成员关系: {}", e);
+    }
+    // 管理命令执行完毕后直接退出 不启动服务器
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|arg| arg.as_str()) == Some("admin") {
+        if let Err(e) = cli::run(&db, &args[2..]).await {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    // 以下只在启动服务器时执行 管理命令可能与正在运行的服务器同时使用数据库
+    match models::users::User::resume_deletions(&db).await {
+        Ok(0) => {}
+        Ok(resumed) => info!("完成了 {} 个被中断的用户删除", resumed),
+        Err(e) => error!("无法继续被中断的用户删除: {}", e),
+    }
+    // 重启前没有完成的导出任务不会再继续
+    match models::export_jobs::ExportJob::fail_stale(&db, chrono::Utc::now()).await {
+        Ok(0) => {}
+        Ok(failed) => info!("{} 个被中断的导出任务标记为失败", failed),
+        Err(e) => error!("无法更新被中断的导出任务: {}", e),
+    }
+    if config.retention.enabled {
+        async_std::task::spawn(sweeper::run(db.clone(), config.retention.clone()));
+    }
     info!("创建服务器实例");
     let address = format!("{}:{}", &config.server.host, &config.server.port);
     let mut app = tide::with_state(AppState {
@@ -49,6 +89,8 @@ async fn main() {
         .allow_methods("GET, POST, PUT, DELETE, OPTIONS, PATCH".parse::<HeaderValue>().unwrap())
         .allow_origin(origins)
         .allow_headers("Content-Type, Authorization, Accept, Origin, X-Requested-With, X-CSRF-Token,X-Forwarded-For".parse::<HeaderValue>().unwrap())
+        // 分页的游标
+        .expose_headers("X-Next-Cursor".parse::<HeaderValue>().unwrap())
         .allow_credentials(true));
     app.with(After(errors::handle));
 
@@ -0,0 +1,108 @@
+use wither::Model;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use wither::bson::{DateTime, doc};
+use wither::bson::oid::ObjectId;
+use wither::mongodb::Database;
+use futures::StreamExt;
+use crate::models::SearchById;
+use crate::roles;
+
+// 令牌的前缀 便于识别
+pub const TOKEN_PREFIX: &str = "sbt_";
+
+// 可授予的权限范围
+pub const SCOPE_RECORDS_READ: &str = "records:read";
+pub const SCOPE_RECORDS_WRITE: &str = "records:write";
+pub const SCOPE_DETECTOR_USE: &str = "detector:use";
+pub const SCOPES: [&str; 3] = [SCOPE_RECORDS_READ, SCOPE_RECORDS_WRITE, SCOPE_DETECTOR_USE];
+
+// 每个权限范围允许使用的角色能力 令牌的能力不会超过账号角色和权限范围的交集
+pub fn scope_capabilities(scope: &str) -> &'static [&'static str] {
+    match scope {
+        SCOPE_RECORDS_READ => &[roles::ACCOUNT, roles::RECORDS_READ],
+        SCOPE_RECORDS_WRITE => &[roles::RECORDS_WRITE, roles::STORAGE_UPLOAD],
+        SCOPE_DETECTOR_USE => &[roles::DETECTOR_USE, roles::STORAGE_UPLOAD],
+        _ => &[],
+    }
+}
+
+// 供脚本和客户端使用的个人API令牌
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(collection_name = "api_tokens")]
+#[model(index(keys = r#"doc!{"hash": 1}"#, options = r#"doc!{"unique": true}"#))]
+pub struct ApiToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // string格式的uid
+    pub user: String,
+    pub name: String,
+    // 令牌的哈希 原始令牌只在创建时返回一次
+    pub hash: String,
+    // 令牌的前几位 用于在列表中区分
+    pub hint: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expire_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<DateTime>,
+}
+
+impl SearchById for ApiToken {}
+
+impl ApiToken {
+    // 生成一个新的令牌 返回令牌本身和原始令牌
+    pub fn generate(user: String, name: String, scopes: Vec<String>, expire_at: Option<DateTime>) -> (Self, String) {
+        let raw = format!("{}{}", TOKEN_PREFIX, crate::session::generate_token());
+        let token = ApiToken {
+            id: None,
+            user,
+            name,
+            hash: crate::session::hash_token(&raw),
+            hint: raw[..TOKEN_PREFIX.len() + 6].to_owned(),
+            scopes,
+            created_at: chrono::Utc::now().into(),
+            expire_at,
+            last_used: None,
+        };
+        (token, raw)
+    }
+    // 查找一个未过期的令牌
+    pub async fn by_raw(db: &Database, raw: &str) -> Option<Self> {
+        let token = ApiToken::find_one(db, Some(doc! {
+            "hash": crate::session::hash_token(raw)
+        }), None).await.unwrap_or(None)?;
+        if let Some(expire_at) = token.expire_at {
+            if expire_at.timestamp() < chrono::Utc::now().timestamp() {
+                return None;
+            }
+        }
+        Some(token)
+    }
+    pub async fn by_user(db: &Database, uid: &str) -> wither::Result<Vec<Self>> {
+        let tokens: Vec<_> = ApiToken::find(db, Some(doc! {"user": uid}), None).await?.collect().await;
+        Ok(tokens.into_iter().filter_map(|t| t.ok()).collect())
+    }
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+    pub async fn touch(&self, db: &Database) {
+        let _ = ApiToken::collection(db).update_one(doc! {
+            "_id": self.id.as_ref().unwrap()
+        }, doc! {
+            "$set": {"last_used": chrono::Utc::now()}
+        }, None).await;
+    }
+    pub fn to_response(&self) -> serde_json::Value {
+        json!({
+            "id": self.id.as_ref().unwrap().to_hex(),
+            "name": self.name,
+            "hint": self.hint,
+            "scopes": self.scopes,
+            "created_at": self.created_at.timestamp(),
+            "expire_at": self.expire_at.map(|t| t.timestamp()),
+            "last_used": self.last_used.map(|t| t.timestamp()),
+        })
+    }
+}
@@ -28,6 +28,29 @@ pub struct Detection {
     pub total: Option<isize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub threshold: Option<f64>,
+    // 调试模式 保存拼接后的Heatmap
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub debug: Option<bool>,
+    // Heatmap图片的Storage id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heatmap: Option<String>,
+    // 开始和结束处理的时间
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime>,
+    // 失败原因
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // 配置文件中的模型版本
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_version: Option<String>,
+    // 执行时模型文件的md5
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_checksum: Option<String>,
+    // 重新执行时 指向最初的任务
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
 }
 
 
@@ -36,23 +59,47 @@ const STD: [f32; 3] = [1.667162, 1.44502, 1.320071];
 const INPUT_SIZE: (usize, usize) = (800, 800);
 const HEATMAP_SIZE: (usize, usize) = (200, 200);
 
+// 图片和视频检测共用的检测参数
+pub fn make_config(window_size: isize, overlap: i8, tile_max_num: i16, model_path: String) -> DetectConfig {
+    DetectConfig {
+        window_size: (window_size as usize, window_size as usize),
+        overlap: overlap as u8,
+        tile_max_num: tile_max_num as u16,
+        input_size: INPUT_SIZE,
+        batch_size: 1,
+        heatmap_size: HEATMAP_SIZE,
+        model_path,
+        mean: MEAN,
+        std: STD,
+    }
+}
+
 impl Detection {
     pub fn get_config(&self, model_path: String) -> DetectConfig {
-        DetectConfig {
-            window_size: (self.window_size as usize, self.window_size as usize),
-            overlap: self.overlap as u8,
-            tile_max_num: self.tile_max_num as u16,
-            input_size: INPUT_SIZE,
-            batch_size: 1,
-            heatmap_size: HEATMAP_SIZE,
-            model_path,
-            mean: MEAN,
-            std: STD,
-        }
+        make_config(self.window_size, self.overlap, self.tile_max_num, model_path)
     }
     pub async fn get_attachment(&self, db: &Database) -> Option<Storage> {
         Storage::by_id(db, &self.attachment).await
     }
+    pub async fn get_heatmap(&self, db: &Database) -> Option<Storage> {
+        if let Some(heatmap) = &self.heatmap {
+            Storage::by_id(db, heatmap).await
+        } else {
+            None
+        }
+    }
+    // 删除调试用的Heatmap 包括文件和Storage记录
+    pub async fn remove_heatmap(&self, db: &Database) -> wither::Result<()> {
+        if let Some(heatmap) = self.get_heatmap(db).await {
+            heatmap.remove(db).await?;
+        }
+        Ok(())
+    }
+    // 超过阈值的检测框数量 任务未完成时为None
+    pub fn count(&self, threshold: f32) -> Option<usize> {
+        self.result.as_ref()
+            .map(|boxes| boxes.iter().filter(|b| b.score >= threshold).count())
+    }
     pub async fn to_status(&self) -> DetectionStatusResponse {
         DetectionStatusResponse {
             status: self.status.clone(),
@@ -74,6 +121,14 @@ impl Detection {
             current: self.current.clone(),
             total: self.total.clone(),
             threshold: self.threshold.clone(),
+            debug: self.debug.unwrap_or(false),
+            heatmap: self.heatmap.clone(),
+            started_at: self.started_at.clone(),
+            finished_at: self.finished_at.clone(),
+            error: self.error.clone(),
+            model_version: self.model_version.clone(),
+            model_checksum: self.model_checksum.clone(),
+            parent: self.parent.clone(),
         }
     }
 }
@@ -106,6 +161,21 @@ pub struct DetectionInfoResponse {
     pub total: Option<isize>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub threshold: Option<f64>,
+    pub debug: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heatmap: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_checksum: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
 }
 
 impl SearchById for Detection {}
\ No newline at end of file
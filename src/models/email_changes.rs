@@ -0,0 +1,63 @@
+use wither::Model;
+use serde::{Serialize, Deserialize};
+use wither::bson::{DateTime, doc};
+use wither::bson::oid::ObjectId;
+use wither::mongodb::Database;
+
+// 同一个验证码最多允许尝试的次数
+pub const MAX_ATTEMPTS: i32 = 5;
+
+// 修改邮箱的请求 确认之前旧邮箱仍然有效
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(collection_name = "email_changes")]
+#[model(index(keys = r#"doc!{"user": 1}"#, options = r#"doc!{"unique": true}"#))]
+#[model(index(keys = r#"doc!{"expire_at": 1}"#, options = r#"doc!{"expireAfterSeconds": 0}"#))]
+pub struct EmailChange {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // string格式的uid 每个用户同时只有一个请求
+    pub user: String,
+    pub new_email: String,
+    // 验证码的哈希
+    pub code: String,
+    // 发送通知邮件时使用的语言
+    pub lang: String,
+    pub expire_at: DateTime,
+    pub attempts: i32,
+}
+
+impl EmailChange {
+    pub fn new(user: String, new_email: String, code: &str, lang: String) -> Self {
+        EmailChange {
+            id: None,
+            user,
+            new_email,
+            code: crate::session::hash_token(code),
+            lang,
+            expire_at: (chrono::Utc::now() + chrono::Duration::hours(1)).into(),
+            attempts: 0,
+        }
+    }
+    // 用新的请求替换用户之前的请求
+    pub async fn replace(db: &Database, change: &mut EmailChange) -> wither::Result<()> {
+        EmailChange::collection(db).delete_many(doc! {"user": &change.user}, None).await?;
+        change.save(db, None).await
+    }
+    pub async fn by_user(db: &Database, uid: &str) -> Option<Self> {
+        EmailChange::find_one(db, Some(doc! {
+            "user": uid,
+            "expire_at": {"$gt": chrono::Utc::now()},
+            "attempts": {"$lt": MAX_ATTEMPTS},
+        }), None).await.unwrap_or(None)
+    }
+    // 校验验证码 失败时增加尝试次数
+    pub async fn check(&self, db: &Database, code: &str) -> wither::Result<bool> {
+        if self.code == crate::session::hash_token(code.trim()) {
+            return Ok(true);
+        }
+        EmailChange::collection(db).update_one(doc! {"_id": self.id.as_ref().unwrap()}, doc! {
+            "$inc": {"attempts": 1}
+        }, None).await?;
+        Ok(false)
+    }
+}
@@ -0,0 +1,128 @@
+use wither::Model;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use log::warn;
+use wither::bson::{DateTime, doc};
+use wither::bson::oid::ObjectId;
+use wither::mongodb::Database;
+use wither::mongodb::options::FindOneOptions;
+use futures::StreamExt;
+use crate::models::SearchById;
+
+// 导出文件的保留时间 超过后需要重新导出
+pub const KEEP_HOURS: i64 = 24;
+// 超过这个时间仍未完成的任务视为失败
+pub const TIMEOUT_MINUTES: i64 = 30;
+
+// 用户数据导出任务
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(collection_name = "export_jobs")]
+pub struct ExportJob {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // string格式的uid
+    pub user: String,
+    // pending / processing / finished / failed / expired
+    pub status: String,
+    pub created_at: DateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime>,
+    // 生成的ZIP文件
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SearchById for ExportJob {}
+
+impl ExportJob {
+    pub fn new(user: String) -> Self {
+        ExportJob {
+            id: None,
+            user,
+            status: "pending".to_string(),
+            created_at: chrono::Utc::now().into(),
+            finished_at: None,
+            local_path: None,
+            error: None,
+        }
+    }
+    // 用户最近的一个导出任务
+    pub async fn latest(db: &Database, uid: &str) -> Option<Self> {
+        let mut opts = FindOneOptions::default();
+        opts.sort = Some(doc! {"created_at": -1});
+        ExportJob::find_one(db, Some(doc! {"user": uid}), Some(opts)).await.unwrap_or(None)
+    }
+    // 还在进行中 或者结果仍然有效
+    pub fn is_reusable(&self) -> bool {
+        match self.status.as_str() {
+            "pending" | "processing" => {
+                let deadline = chrono::Utc::now() - chrono::Duration::minutes(TIMEOUT_MINUTES);
+                self.created_at.timestamp() > deadline.timestamp()
+            }
+            "finished" => {
+                let deadline = chrono::Utc::now() - chrono::Duration::hours(KEEP_HOURS);
+                self.created_at.timestamp() > deadline.timestamp()
+            }
+            _ => false,
+        }
+    }
+    // 将在给定时间之前创建 但仍未完成的任务标记为失败
+    // 服务器重启后 之前的导出线程已经不存在了
+    pub async fn fail_stale(db: &Database, before: chrono::DateTime<chrono::Utc>) -> wither::Result<i64> {
+        let result = ExportJob::collection(db).update_many(doc! {
+            "status": {"$in": ["pending", "processing"]},
+            "created_at": {"$lt": before},
+        }, doc! {
+            "$set": {"status": "failed", "error": "导出超时", "finished_at": chrono::Utc::now()},
+        }, None).await?;
+        Ok(result.modified_count)
+    }
+    // 删除超过保留时间的导出文件
+    pub async fn expire(db: &Database) -> wither::Result<usize> {
+        let deadline = chrono::Utc::now() - chrono::Duration::hours(KEEP_HOURS);
+        let jobs: Vec<_> = ExportJob::find(db, Some(doc! {
+            "status": "finished",
+            "created_at": {"$lt": deadline},
+        }), None).await?.collect().await;
+        let mut expired = 0;
+        for job in jobs.into_iter().flatten() {
+            if let Some(path) = &job.local_path {
+                if let Err(e) = async_std::fs::remove_file(path).await {
+                    warn!("无法删除导出文件 {}: {}", path, e);
+                    continue;
+                }
+            }
+            ExportJob::collection(db).update_one(doc! {"_id": job.id.clone().unwrap()}, doc! {
+                "$set": {"status": "expired"},
+                "$unset": {"local_path": ""},
+            }, None).await?;
+            expired += 1;
+        }
+        Ok(expired)
+    }
+    // 删除用户所有的导出任务和文件
+    pub async fn clear_user(db: &Database, uid: &str) -> wither::Result<()> {
+        let jobs: Vec<_> = ExportJob::find(db, Some(doc! {"user": uid}), None).await?.collect().await;
+        for job in jobs.into_iter().flatten() {
+            if let Some(path) = &job.local_path {
+                if let Err(e) = async_std::fs::remove_file(path).await {
+                    warn!("无法删除导出文件 {}: {}", path, e);
+                }
+            }
+            job.delete(db).await?;
+        }
+        Ok(())
+    }
+    pub fn to_response(&self) -> serde_json::Value {
+        json!({
+            "id": self.id.as_ref().unwrap().to_hex(),
+            "status": self.status,
+            "created_at": self.created_at.timestamp(),
+            "finished_at": self.finished_at.map(|t| t.timestamp()),
+            "error": self.error,
+            "download": if self.status == "finished" { Some("/users/me/export/download") } else { None },
+        })
+    }
+}
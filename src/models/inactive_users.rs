@@ -4,10 +4,19 @@ use serde::{Serialize, Deserialize};
 use wither::bson::{DateTime, doc};
 use wither::bson::oid::ObjectId;
 use wither::mongodb::Database;
+use futures::StreamExt;
 use crate::models::users::User;
 
+// 同一个验证码最多允许尝试的次数
+pub const MAX_ATTEMPTS: i32 = 5;
+// 两次发送验证码之间至少间隔的秒数
+pub const RESEND_INTERVAL: i64 = 60;
+
 #[derive(Debug, Model, Serialize, Deserialize, Clone)]
 #[model(collection_name = "inactive_users")]
+#[model(index(keys = r#"doc!{"email": 1}"#))]
+// 过期的注册请求由MongoDB自动清理
+#[model(index(keys = r#"doc!{"expire_at": 1}"#, options = r#"doc!{"expireAfterSeconds": 0}"#))]
 pub struct InactiveUser {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
@@ -17,19 +26,92 @@ pub struct InactiveUser {
     pub password: String,
     pub permission: i8,
     pub name: String,
-    // 验证码
+    // 验证码的哈希
     pub code: String,
     pub expire_at: DateTime, // 过期时间
+    // 已经尝试的次数
+    #[serde(default)]
+    pub attempts: i32,
+    // 上次发送验证码的时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime>,
+    // 注册时使用的邀请ID 激活时才计入使用次数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invitation: Option<String>,
+    // 验证码已经通过 正在创建用户 防止同一个验证码被并发使用
+    #[serde(default)]
+    pub claimed: bool,
 }
 
 impl InactiveUser {
-    pub async fn by_code(db: &Database, code: String) -> Option<InactiveUser> {
-        if let Ok(res) = InactiveUser::find_one(db, Some(doc! {"code": code}), None).await {
-            res
-        } else {
-            None
+    // 尚未过期的注册请求
+    pub async fn pending(db: &Database, email: &str) -> wither::Result<Vec<Self>> {
+        let users: Vec<_> = InactiveUser::find(db, Some(doc! {
+            "email": email,
+            "expire_at": {"$gt": chrono::Utc::now()},
+        }), None).await?.collect().await;
+        Ok(users.into_iter().flatten().collect())
+    }
+    // 设置新的验证码 重置尝试次数并延长过期时间
+    pub fn reissue(&mut self, code: &str) {
+        let now = chrono::Utc::now();
+        self.code = crate::session::hash_token(code);
+        self.attempts = 0;
+        self.claimed = false;
+        self.sent_at = Some(now.into());
+        self.expire_at = (now + chrono::Duration::hours(1)).into();
+    }
+    pub fn can_resend(&self) -> bool {
+        match self.sent_at {
+            Some(sent_at) => chrono::Utc::now().timestamp() - sent_at.timestamp() >= RESEND_INTERVAL,
+            None => true,
         }
     }
+    // 查找与邮箱和验证码匹配的注册请求 不匹配时所有候选请求的尝试次数都会增加
+    // 匹配的请求只会被标记 用户保存成功后再调用consume删除
+    pub async fn claim(db: &Database, email: &str, code: &str) -> wither::Result<Option<Self>> {
+        let candidates: Vec<_> = InactiveUser::find(db, Some(doc! {
+            "email": email,
+            "expire_at": {"$gt": chrono::Utc::now()},
+            "attempts": {"$lt": MAX_ATTEMPTS},
+            "claimed": {"$ne": true},
+        }), None).await?.collect().await;
+        let hash = crate::session::hash_token(code.trim());
+        for candidate in candidates.into_iter().flatten() {
+            if candidate.code == hash {
+                // 验证码只能使用一次 标记失败说明已被并发的请求使用
+                let result = InactiveUser::collection(db).update_one(doc! {
+                    "_id": candidate.id.as_ref().unwrap(),
+                    "claimed": {"$ne": true},
+                }, doc! {
+                    "$set": {"claimed": true}
+                }, None).await?;
+                return Ok(if result.modified_count == 1 { Some(candidate) } else { None });
+            }
+        }
+        InactiveUser::collection(db).update_many(doc! {
+            "email": email,
+        }, doc! {
+            "$inc": {"attempts": 1}
+        }, None).await?;
+        Ok(None)
+    }
+    // 创建用户失败 允许再次使用这个验证码
+    pub async fn release(&self, db: &Database) -> wither::Result<()> {
+        InactiveUser::collection(db).update_one(doc! {
+            "_id": self.id.as_ref().unwrap(),
+        }, doc! {
+            "$set": {"claimed": false}
+        }, None).await?;
+        Ok(())
+    }
+    // 用户已经创建 删除注册请求
+    pub async fn consume(&self, db: &Database) -> wither::Result<()> {
+        InactiveUser::collection(db).delete_one(doc! {
+            "_id": self.id.as_ref().unwrap(),
+        }, None).await?;
+        Ok(())
+    }
     pub fn to_user(self) -> User {
         // 这个方法消耗自身
         User {
@@ -41,6 +123,13 @@ impl InactiveUser {
             name: self.name,
             created_at: chrono::Utc::now().into(),
             avatar: None,
+            avatar_sizes: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+            recovery_codes: None,
+            deleting: None,
+            role: Some(crate::roles::role_for_level(self.permission).to_owned()),
         }
     }
     // pub fn to_response(self) -> serde_json::Value {
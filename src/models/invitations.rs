@@ -1,12 +1,18 @@
 use wither::Model;
 use serde::{Serialize, Deserialize};
 use serde_json::json;
-use wither::bson::{DateTime, doc};
+use futures::StreamExt;
+use wither::bson::{DateTime, doc, Document};
 use wither::bson::oid::ObjectId;
 use wither::mongodb::Database;
+use wither::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use crate::models::SearchById;
 
 #[derive(Debug, Model, Serialize, Deserialize, Clone)]
 #[model(collection_name = "invitations")]
+#[model(index(keys = r#"doc!{"code": 1}"#))]
+#[model(index(keys = r#"doc!{"creator": 1}"#))]
+#[model(index(keys = r#"doc!{"groups": 1}"#))]
 pub struct Invitation {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
@@ -15,16 +21,80 @@ pub struct Invitation {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub groups: Option<Vec<String>>,
     pub permission: i8,
+    // 创建者的uid 旧版的邀请没有这个字段
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    // 最多可以使用的次数 为空时不限制
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<i32>,
+    #[serde(default)]
+    pub uses: i32,
+    // 使用过这个邀请的用户
+    #[serde(default)]
+    pub redeemed_by: Vec<String>,
+    #[serde(default)]
+    pub revoked: bool,
 }
 
+impl SearchById for Invitation {}
+
 impl Invitation {
-    pub async fn by_code(db: &Database, code: String) -> Option<Invitation> {
-        if let Ok(res) = Invitation::find_one(db, Some(doc! {"code": code}), None).await {
-            res
-        } else {
-            None
+    pub fn new(code: String, creator: &str, expire_at: DateTime, groups: Option<Vec<String>>, permission: i8, max_uses: Option<i32>) -> Self {
+        Invitation {
+            id: None,
+            code,
+            expire_at,
+            groups,
+            permission,
+            creator: Some(creator.to_owned()),
+            created_at: Some(chrono::Utc::now().into()),
+            max_uses,
+            uses: 0,
+            redeemed_by: vec![],
+            revoked: false,
         }
     }
+    // 仍然可以使用的邀请 未过期 未撤销 并且还有剩余次数
+    fn available_filter() -> Document {
+        doc! {
+            "expire_at": {"$gt": chrono::Utc::now()},
+            "revoked": {"$ne": true},
+            "$or": [
+                {"max_uses": null},
+                {"$expr": {"$lt": [{"$ifNull": ["$uses", 0]}, "$max_uses"]}},
+            ],
+        }
+    }
+    pub async fn by_code(db: &Database, code: String) -> Option<Invitation> {
+        let mut filter = Invitation::available_filter();
+        filter.insert("code", code);
+        Invitation::find_one(db, Some(filter), None).await.unwrap_or(None)
+    }
+    // 使用一次邀请 邀请已经失效时返回None
+    pub async fn redeem(db: &Database, id: &ObjectId, uid: &str) -> wither::Result<Option<Invitation>> {
+        let mut filter = Invitation::available_filter();
+        filter.insert("_id", id.clone());
+        let mut opts = FindOneAndUpdateOptions::default();
+        opts.return_document = Some(ReturnDocument::After);
+        Invitation::find_one_and_update(db, filter, doc! {
+            "$inc": {"uses": 1},
+            "$push": {"redeemed_by": uid},
+        }, Some(opts)).await
+    }
+    pub async fn by_creator(db: &Database, uid: &str) -> wither::Result<Vec<Self>> {
+        Invitation::list(db, doc! {"creator": uid}).await
+    }
+    pub async fn by_group(db: &Database, group_id: &str) -> wither::Result<Vec<Self>> {
+        Invitation::list(db, doc! {"groups": group_id}).await
+    }
+    async fn list(db: &Database, filter: Document) -> wither::Result<Vec<Self>> {
+        let mut opts = FindOptions::default();
+        opts.sort = Some(doc! {"_id": -1});
+        let invitations: Vec<_> = Invitation::find(db, Some(filter), Some(opts)).await?.collect().await;
+        Ok(invitations.into_iter().filter_map(|i| i.ok()).collect())
+    }
     pub(crate) fn to_response(self) -> serde_json::Value {
         json!({
             "id": self.id.unwrap().to_hex(),
@@ -34,4 +104,23 @@ impl Invitation {
             "permission": self.permission,
         })
     }
-}
\ No newline at end of file
+    // 邀请列表中的一项 包含使用情况 只给创建者和管理员看
+    pub fn to_detail(self) -> serde_json::Value {
+        let expired = self.expire_at.timestamp() <= chrono::Utc::now().timestamp();
+        let exhausted = self.max_uses.is_some_and(|max| self.uses >= max);
+        json!({
+            "id": self.id.unwrap().to_hex(),
+            "code": self.code,
+            "expire_at": self.expire_at.timestamp(),
+            "groups": self.groups,
+            "permission": self.permission,
+            "creator": self.creator,
+            "created_at": self.created_at.map(|t| t.timestamp()),
+            "max_uses": self.max_uses,
+            "uses": self.uses,
+            "redeemed_by": self.redeemed_by,
+            "revoked": self.revoked,
+            "available": !(self.revoked || expired || exhausted),
+        })
+    }
+}
@@ -0,0 +1,102 @@
+use wither::Model;
+use serde::{Serialize, Deserialize};
+use serde_json::json;
+use wither::bson::{DateTime, doc};
+use wither::bson::oid::ObjectId;
+use wither::mongodb::Database;
+use wither::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use crate::models::SearchById;
+
+// 登录失败记录 按账号和IP分别统计
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(collection_name = "login_attempts")]
+// 每个key只有一条记录 并发的upsert不会产生重复的计数
+#[model(index(keys = r#"doc!{"key": 1}"#, options = r#"doc!{"unique": true}"#))]
+pub struct LoginAttempt {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // user:<uid> ip:<address> 或 reset:<address>
+    pub key: String,
+    // 连续失败次数
+    pub failures: i32,
+    pub last_failure: DateTime,
+    // 在此之前拒绝登录
+    pub blocked_until: DateTime,
+    // 是否已被锁定 否则只是在退避
+    pub locked: bool,
+}
+
+impl SearchById for LoginAttempt {}
+
+impl LoginAttempt {
+    pub fn user_key(user_id: &str) -> String {
+        format!("user:{}", user_id)
+    }
+    pub fn ip_key(ip: &str) -> String {
+        format!("ip:{}", ip)
+    }
+    // 重置密码的请求 与登录分开计数
+    pub fn reset_key(ip: &str) -> String {
+        format!("reset:{}", ip)
+    }
+    pub async fn by_key(db: &Database, key: &str) -> Option<Self> {
+        LoginAttempt::find_one(db, Some(doc! {"key": key}), None).await.unwrap_or(None)
+    }
+    // 还需要等待的秒数
+    pub fn retry_after(&self) -> Option<i64> {
+        let wait = self.blocked_until.timestamp() - chrono::Utc::now().timestamp();
+        if wait > 0 {
+            Some(wait)
+        } else {
+            None
+        }
+    }
+    // 记录一次失败 并计算下次允许尝试的时间
+    pub async fn record_failure(db: &Database, key: &str, max_failures: i32, config: &crate::config::LoginConfig) -> wither::Result<Self> {
+        let now = chrono::Utc::now();
+        // 距上次失败已经超过锁定时长 重新计数
+        let stale = now - chrono::Duration::seconds(config.lockout);
+        LoginAttempt::collection(db).delete_one(doc! {
+            "key": key,
+            "last_failure": {"$lt": stale},
+        }, None).await?;
+        let mut opts = FindOneAndUpdateOptions::default();
+        opts.upsert = Some(true);
+        opts.return_document = Some(ReturnDocument::After);
+        // 使用$inc保证并发请求也能被正确计数
+        let attempt = LoginAttempt::find_one_and_update(db, doc! {"key": key}, doc! {
+            "$inc": {"failures": 1},
+            "$set": {"last_failure": now},
+            "$setOnInsert": {"blocked_until": now, "locked": false},
+        }, Some(opts)).await?.unwrap();
+        let (blocked_until, locked) = if attempt.failures >= max_failures {
+            (now + chrono::Duration::seconds(config.lockout), true)
+        } else {
+            let exponent = (attempt.failures - 1).clamp(0, 30) as u32;
+            let backoff = config.backoff_base.saturating_mul(2i64.pow(exponent)).min(config.backoff_max);
+            (now + chrono::Duration::seconds(backoff), false)
+        };
+        let mut opts = FindOneAndUpdateOptions::default();
+        opts.return_document = Some(ReturnDocument::After);
+        attempt.update(db, None, doc! {
+            "$set": {
+                "blocked_until": blocked_until,
+                "locked": locked,
+            }
+        }, Some(opts)).await
+    }
+    pub async fn clear(db: &Database, key: &str) -> wither::Result<()> {
+        LoginAttempt::collection(db).delete_many(doc! {"key": key}, None).await?;
+        Ok(())
+    }
+    pub fn to_response(&self) -> serde_json::Value {
+        json!({
+            "id": self.id.as_ref().unwrap().to_hex(),
+            "key": self.key,
+            "failures": self.failures,
+            "last_failure": self.last_failure.timestamp(),
+            "blocked_until": self.blocked_until.timestamp(),
+            "locked": self.locked,
+        })
+    }
+}
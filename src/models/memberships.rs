@@ -0,0 +1,148 @@
+use wither::Model;
+use serde::{Serialize, Deserialize};
+use log::info;
+use futures::StreamExt;
+use wither::bson::{DateTime, doc};
+use wither::bson::oid::ObjectId;
+use wither::mongodb::Database;
+use wither::mongodb::options::UpdateOptions;
+
+// 小组内的角色
+pub const MEMBER: &str = "member";
+pub const MANAGER: &str = "manager";
+
+// 用户在某个小组中的成员关系
+// User.groups 和 Group.managers 只是冗余的副本 由这里的方法同步修改
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(collection_name = "memberships")]
+#[model(index(keys = r#"doc!{"user": 1, "group": 1}"#, options = r#"doc!{"unique": true}"#))]
+#[model(index(keys = r#"doc!{"group": 1}"#))]
+pub struct Membership {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // string格式的uid
+    pub user: String,
+    // string格式的小组ID
+    pub group: String,
+    pub role: String,
+    pub created_at: DateTime,
+}
+
+impl Membership {
+    pub async fn find_for(db: &Database, uid: &str, group_id: &str) -> Option<Self> {
+        Membership::find_one(db, Some(doc! {
+            "user": uid,
+            "group": group_id,
+        }), None).await.unwrap_or(None)
+    }
+    pub async fn is_manager(db: &Database, uid: &str, group_id: &str) -> bool {
+        matches!(Membership::find_for(db, uid, group_id).await, Some(m) if m.role == MANAGER)
+    }
+    // 用户担任管理员的所有小组
+    pub async fn managed_groups(db: &Database, uid: &str) -> wither::Result<Vec<String>> {
+        let memberships: Vec<_> = Membership::find(db, Some(doc! {
+            "user": uid,
+            "role": MANAGER,
+        }), None).await?.collect().await;
+        Ok(memberships.into_iter().filter_map(|m| m.ok()).map(|m| m.group).collect())
+    }
+    // 加入小组 已经是成员时修改角色
+    pub async fn join(db: &Database, uid: &str, group_id: &str, role: &str) -> wither::Result<()> {
+        let mut opts = UpdateOptions::default();
+        opts.upsert = Some(true);
+        Membership::collection(db).update_one(doc! {
+            "user": uid,
+            "group": group_id,
+        }, doc! {
+            "$set": {"role": role},
+            "$setOnInsert": {"created_at": chrono::Utc::now()},
+        }, Some(opts)).await?;
+        if let Ok(oid) = ObjectId::with_string(uid) {
+            db.collection("users").update_one(doc! {"_id": oid}, doc! {
+                "$addToSet": {"groups": group_id}
+            }, None).await?;
+        }
+        if let Ok(oid) = ObjectId::with_string(group_id) {
+            let managers = if role == MANAGER {
+                doc! {"$addToSet": {"managers": uid}}
+            } else {
+                doc! {"$pull": {"managers": uid}}
+            };
+            db.collection("groups").update_one(doc! {"_id": oid}, managers, None).await?;
+        }
+        Ok(())
+    }
+    // 退出小组
+    pub async fn leave(db: &Database, uid: &str, group_id: &str) -> wither::Result<()> {
+        Membership::collection(db).delete_many(doc! {
+            "user": uid,
+            "group": group_id,
+        }, None).await?;
+        if let Ok(oid) = ObjectId::with_string(uid) {
+            db.collection("users").update_one(doc! {"_id": oid}, doc! {
+                "$pull": {"groups": group_id}
+            }, None).await?;
+        }
+        if let Ok(oid) = ObjectId::with_string(group_id) {
+            db.collection("groups").update_one(doc! {"_id": oid}, doc! {
+                "$pull": {"managers": uid}
+            }, None).await?;
+        }
+        Ok(())
+    }
+    // 将小组的管理员设置为给定的列表 其余管理员降为普通成员
+    pub async fn set_managers(db: &Database, group_id: &str, managers: &[String]) -> wither::Result<()> {
+        let current: Vec<_> = Membership::find(db, Some(doc! {
+            "group": group_id,
+            "role": MANAGER,
+        }), None).await?.collect().await;
+        for membership in current.into_iter().filter_map(|m| m.ok()) {
+            if !managers.contains(&membership.user) {
+                Membership::join(db, &membership.user, group_id, MEMBER).await?;
+            }
+        }
+        for manager in managers {
+            Membership::join(db, manager, group_id, MANAGER).await?;
+        }
+        Ok(())
+    }
+    // 根据旧的 User.groups 和 Group.managers 创建成员关系
+    // 已经存在的成员关系不会被修改
+    pub async fn migrate(db: &Database) -> wither::Result<i64> {
+        let mut opts = UpdateOptions::default();
+        opts.upsert = Some(true);
+        let mut migrated = 0;
+        let groups: Vec<_> = crate::models::groups::Group::find(db, None, None).await?.collect().await;
+        for group in groups.into_iter().filter_map(|g| g.ok()) {
+            let group_id = group.id.as_ref().unwrap().to_hex();
+            let users: Vec<_> = crate::models::users::User::find(db, Some(doc! {
+                "groups": &group_id
+            }), None).await?.collect().await;
+            let mut members: Vec<String> = users.into_iter()
+                .filter_map(|u| u.ok())
+                .map(|u| u.id.unwrap().to_hex())
+                .collect();
+            for manager in &group.managers {
+                if !members.contains(manager) {
+                    members.push(manager.to_owned());
+                }
+            }
+            for uid in members {
+                let role = if group.managers.contains(&uid) { MANAGER } else { MEMBER };
+                let result = Membership::collection(db).update_one(doc! {
+                    "user": &uid,
+                    "group": &group_id,
+                }, doc! {
+                    "$setOnInsert": {"role": role, "created_at": chrono::Utc::now()}
+                }, Some(opts.clone())).await?;
+                if result.upserted_id.is_some() {
+                    migrated += 1;
+                }
+            }
+        }
+        if migrated > 0 {
+            info!("创建了 {} 条小组成员关系", migrated);
+        }
+        Ok(migrated)
+    }
+}
@@ -8,6 +8,13 @@ pub mod positions;
 pub mod groups;
 pub mod records;
 pub mod projects;
+pub mod video_detections;
+pub mod login_attempts;
+pub mod api_tokens;
+pub mod password_resets;
+pub mod email_changes;
+pub mod export_jobs;
+pub mod memberships;
 
 use wither::bson::{DateTime, doc, oid::ObjectId};
 use serde::{Serialize, Deserialize};
@@ -15,15 +22,20 @@ use wither::Model;
 use wither::mongodb::Database;
 use wither::mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use crate::models::users::User;
+use crate::config::Config;
+use futures::StreamExt;
 
 
 #[derive(Debug, Model, Serialize, Deserialize, Clone)]
 #[model(collection_name = "sessions")]
+#[model(index(keys = r#"doc!{"fingerprint": 1}"#))]
+// 过期的session由MongoDB自动清理
+#[model(index(keys = r#"doc!{"expire_at": 1}"#, options = r#"doc!{"expireAfterSeconds": 0}"#))]
 pub struct Session {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub fingerprint: String,
-    // 指纹
+    // 令牌的哈希 原始令牌只保存在cookie中
     pub login: bool,
     // 是否登录
     pub permission: i8,
@@ -34,14 +46,32 @@ pub struct Session {
     pub expire_at: DateTime,
     // 过期时间
     pub ip: String, // ip地址
+    // 客户端的User-Agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    // 创建时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    // 最后一次活动的时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime>,
+    // 新生成的原始令牌 不保存到数据库 由中间件写入cookie
+    #[serde(skip)]
+    pub token: Option<String>,
+    // 角色 旧版的session没有这个字段 按照数字权限推断
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    // 通过API令牌认证时 令牌拥有的权限范围
+    #[serde(skip)]
+    pub scopes: Option<Vec<String>>,
 }
 
 // 用于转换为response的结构体
 #[derive(Debug, Serialize, Clone)]
 pub struct SessionResponse {
-    pub fingerprint: String,
     pub login: bool,
     pub permission: i8,
+    pub role: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<serde_json::Value>,
     pub expire_at: i64,
@@ -49,14 +79,57 @@ pub struct SessionResponse {
 }
 
 impl Session {
+    // 重新生成令牌 登录和注销时调用 防止会话固定攻击
+    // 调用者需要保存session 并将其放入响应的ext中
+    pub fn rotate(&mut self) {
+        let token = crate::session::generate_token();
+        self.fingerprint = crate::session::hash_token(&token);
+        self.token = Some(token);
+    }
+    pub fn role_name(&self) -> &str {
+        self.role.as_deref().unwrap_or_else(|| crate::roles::role_for_level(self.permission))
+    }
+    // 将session授予某个用户
+    // 要求两步验证但尚未启用的用户只能获得普通用户的权限
+    pub fn grant(&mut self, user: &User, config: &Config) {
+        let permission = user.permission as i8;
+        self.login = true;
+        self.user = Some(user.id.as_ref().unwrap().to_hex());
+        if !user.totp_enabled && config.two_factor.is_required(&config.roles, permission, user.role_name()) {
+            self.permission = permission.min(1);
+            self.role = Some(crate::roles::role_for_level(self.permission).to_owned());
+        } else {
+            self.permission = permission;
+            self.role = Some(user.role_name().to_owned());
+        }
+    }
     pub async fn find_by_fingerprint(db: &Database, fingerprint: &str) -> Option<Self> {
         Session::find_one(db, Some(doc! {"fingerprint": fingerprint}), None).await.unwrap_or(None)
     }
+    // 查找某个用户的所有已登录的session
+    pub async fn by_user(db: &Database, uid: &str) -> wither::Result<Vec<Self>> {
+        let sessions: Vec<_> = Session::find(db, Some(doc! {
+            "user": uid,
+            "login": true,
+        }), None).await?.collect().await;
+        Ok(sessions.into_iter().filter_map(|s| s.ok()).collect())
+    }
+    // 注销某个用户的所有session 可以保留当前的session
+    pub async fn revoke_all(db: &Database, uid: &str, except: Option<&ObjectId>) -> wither::Result<i64> {
+        let mut filter = doc! {"user": uid};
+        if let Some(except) = except {
+            filter.insert("_id", doc! {"$ne": except});
+        }
+        let result = Session::collection(db).delete_many(filter, None).await?;
+        Ok(result.deleted_count)
+    }
     pub async fn update_timeout(self, db: &Database, timeout: u64) -> wither::Result<Self> {
-        let expire_at = chrono::prelude::Utc::now() + chrono::Duration::seconds(timeout as i64);
+        let now = chrono::prelude::Utc::now();
+        let expire_at = now + chrono::Duration::seconds(timeout as i64);
         let update = doc! {
             "$set": doc!{
                 "expire_at": expire_at,
+                "last_seen": now,
             }
         };
         let mut opts = FindOneAndUpdateOptions::default();
@@ -76,24 +149,51 @@ impl Session {
     pub async fn to_response(&self, db: &Database) -> SessionResponse {
         if let Some(user_id) = &self.user {
             SessionResponse {
-                fingerprint: self.fingerprint.clone(),
                 login: self.login,
                 permission: self.permission,
+                role: self.role_name().to_owned(),
                 user: Some(User::by_id(db, user_id).await.unwrap().to_response(&db).await),
                 expire_at: self.expire_at.timestamp(),
                 ip: self.ip.clone(),
             }
         } else {
             SessionResponse {
-                fingerprint: self.fingerprint.clone(),
                 login: self.login,
                 permission: self.permission,
+                role: self.role_name().to_owned(),
                 user: None,
                 expire_at: self.expire_at.timestamp(),
                 ip: self.ip.clone(),
             }
         }
     }
+    // 会话列表中的一项
+    pub fn to_item(&self, current: bool) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id.as_ref().unwrap().to_hex(),
+            "ip": self.ip,
+            "user_agent": self.user_agent,
+            "created_at": self.created_at.map(|t| t.timestamp()),
+            "last_seen": self.last_seen.map(|t| t.timestamp()),
+            "expire_at": self.expire_at.timestamp(),
+            "current": current,
+        })
+    }
+}
+
+impl SearchById for Session {}
+
+// 启动时同步各集合的索引
+pub async fn sync_indexes(db: &Database) -> wither::Result<()> {
+    Session::sync(db).await?;
+    api_tokens::ApiToken::sync(db).await?;
+    password_resets::PasswordReset::sync(db).await?;
+    email_changes::EmailChange::sync(db).await?;
+    memberships::Membership::sync(db).await?;
+    invitations::Invitation::sync(db).await?;
+    inactive_users::InactiveUser::sync(db).await?;
+    login_attempts::LoginAttempt::sync(db).await?;
+    Ok(())
 }
 
 #[async_trait::async_trait]
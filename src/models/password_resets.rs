@@ -0,0 +1,93 @@
+use wither::Model;
+use serde::{Serialize, Deserialize};
+use wither::bson::{DateTime, doc};
+use wither::bson::oid::ObjectId;
+use wither::mongodb::Database;
+use futures::StreamExt;
+
+// 同一个验证码最多允许尝试的次数
+pub const MAX_ATTEMPTS: i32 = 5;
+// 两次发送验证码之间至少间隔的秒数
+pub const RESEND_INTERVAL: i64 = 60;
+
+// 通过邮件验证码重置密码的请求
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(collection_name = "password_resets")]
+#[model(index(keys = r#"doc!{"email": 1}"#))]
+#[model(index(keys = r#"doc!{"expire_at": 1}"#, options = r#"doc!{"expireAfterSeconds": 0}"#))]
+pub struct PasswordReset {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    // string格式的uid
+    pub user: String,
+    pub email: String,
+    // 验证码的哈希
+    pub code: String,
+    pub expire_at: DateTime,
+    // 已经尝试的次数 重新发送验证码时不会清零
+    pub attempts: i32,
+    // 上次发送验证码的时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime>,
+}
+
+impl PasswordReset {
+    pub fn new(user: String, email: String, code: &str, minutes: i64) -> Self {
+        PasswordReset {
+            id: None,
+            user,
+            email,
+            code: crate::session::hash_token(code),
+            expire_at: (chrono::Utc::now() + chrono::Duration::minutes(minutes)).into(),
+            attempts: 0,
+            sent_at: Some(chrono::Utc::now().into()),
+        }
+    }
+    // 用户尚未过期的重置请求
+    pub async fn pending(db: &Database, uid: &str) -> wither::Result<Option<Self>> {
+        PasswordReset::find_one(db, Some(doc! {
+            "user": uid,
+            "expire_at": {"$gt": chrono::Utc::now()},
+        }), None).await
+    }
+    pub fn can_resend(&self) -> bool {
+        match self.sent_at {
+            Some(sent_at) => chrono::Utc::now().timestamp() - sent_at.timestamp() >= RESEND_INTERVAL,
+            None => true,
+        }
+    }
+    // 设置新的验证码 尝试次数和过期时间保持不变 避免通过重新发送获得更多的尝试机会
+    pub fn reissue(&mut self, code: &str) {
+        self.code = crate::session::hash_token(code);
+        self.sent_at = Some(chrono::Utc::now().into());
+    }
+    // 作废某个用户之前的所有重置请求
+    pub async fn clear_user(db: &Database, uid: &str) -> wither::Result<()> {
+        PasswordReset::collection(db).delete_many(doc! {"user": uid}, None).await?;
+        Ok(())
+    }
+    // 查找与邮箱和验证码匹配的请求 不匹配时所有候选请求的尝试次数都会增加
+    pub async fn redeem(db: &Database, email: &str, code: &str) -> wither::Result<Option<Self>> {
+        let now = chrono::Utc::now();
+        let candidates: Vec<_> = PasswordReset::find(db, Some(doc! {
+            "email": email,
+            "expire_at": {"$gt": now},
+            "attempts": {"$lt": MAX_ATTEMPTS},
+        }), None).await?.collect().await;
+        let hash = crate::session::hash_token(code);
+        for candidate in candidates.into_iter().flatten() {
+            if candidate.code == hash {
+                // 验证码只能使用一次 删除失败说明已被并发的请求使用
+                let result = PasswordReset::collection(db)
+                    .delete_one(doc! {"_id": candidate.id.as_ref().unwrap()}, None).await?;
+                return Ok(if result.deleted_count == 1 { Some(candidate) } else { None });
+            }
+        }
+        PasswordReset::collection(db).update_many(doc! {
+            "email": email,
+        }, doc! {
+            "$inc": {"attempts": 1}
+        }, None).await?;
+        Ok(None)
+    }
+}
@@ -3,6 +3,8 @@ use wither::bson::oid::ObjectId;
 use wither::Model;
 use serde::{Serialize, Deserialize};
 use serde_json::json;
+use log::warn;
+use wither::mongodb::Database;
 use crate::models::SearchById;
 
 #[derive(Debug, Model, Serialize, Deserialize, Clone)]
@@ -21,6 +23,32 @@ pub struct Storage {
 impl SearchById for Storage {}
 
 impl Storage {
+    // 同时删除文件和数据库记录
+    pub async fn remove(&self, db: &Database) -> wither::Result<()> {
+        if let Err(e) = async_std::fs::remove_file(&self.local_path).await {
+            warn!("无法删除文件 {}: {}", self.local_path, e);
+        }
+        self.delete(db).await?;
+        Ok(())
+    }
+    // 检查这个文件是否还被记录 检测任务 用户头像或小组封面引用
+    pub async fn in_use(db: &Database, id: &str) -> bool {
+        let references = [
+            ("records", doc! {"attachments": id}),
+            ("detections", doc! {"attachment": id}),
+            ("video_detections", doc! {"attachment": id}),
+            ("users", doc! {"avatar": id}),
+            ("users", doc! {"avatar_sizes.id": id}),
+            ("groups", doc! {"cover": id}),
+        ];
+        for (collection, filter) in references {
+            // 查询失败时保守处理 视为仍被引用
+            if db.collection(collection).count_documents(filter, None).await.unwrap_or(1) > 0 {
+                return true;
+            }
+        }
+        false
+    }
     pub fn to_response(self) -> serde_json::Value {
         json!({
             "id": self.id.unwrap().to_hex(),
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use wither::bson::{DateTime, doc};
 use wither::bson::oid::ObjectId;
 use wither::mongodb::Database;
@@ -21,6 +22,50 @@ pub struct User {
     pub permission: f64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar: Option<String>,
+    // 上传头像时生成的各个尺寸 avatar为其中最大的一个
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_sizes: Option<Vec<AvatarSize>>,
+    // 两步验证的密钥 确认之前totp_enabled为false
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    // 最后一次使用的验证码所在的周期 同一个验证码不能使用两次
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_last_step: Option<i64>,
+    // 恢复码的哈希 每个只能使用一次
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+    // 角色 决定用户拥有的能力 启动时会为旧用户补上
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    // 正在被删除 中断后可以根据这个标记继续
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleting: Option<DeletionPlan>,
+}
+
+// 匿名化后数据的归属 不能与未登录用户使用的 "anonymous" 相同
+// 否则会被当作匿名任务清理 或者被未登录的用户删除
+pub const DELETED_OWNER: &str = "deleted";
+
+// 删除用户时如何处理其数据
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum DeletionPlan {
+    // 把数据转交给另一个用户
+    Reassign { to: String },
+    // 数据保留 但不再关联到任何用户
+    Anonymize,
+}
+
+impl DeletionPlan {
+    // 数据的新归属
+    fn new_owner(&self) -> &str {
+        match self {
+            DeletionPlan::Reassign { to } => to,
+            DeletionPlan::Anonymize => DELETED_OWNER,
+        }
+    }
 }
 
 impl User {
@@ -52,6 +97,28 @@ impl User {
         for group_id in self.groups.as_ref().unwrap_or(&Vec::new()) {
             groups.push(Group::by_id(&db, group_id).await.unwrap().to_response())
         }
+        self.response_with(groups)
+    }
+    // 转换一组用户 所有的小组只查询一次
+    pub async fn to_responses(db: &Database, users: &[User]) -> wither::Result<Vec<serde_json::Value>> {
+        let ids: Vec<ObjectId> = users.iter()
+            .flat_map(|user| user.groups.iter().flatten())
+            .filter_map(|id| ObjectId::with_string(id).ok())
+            .collect();
+        let groups: Vec<_> = Group::find(db, Some(doc! {"_id": {"$in": ids}}), None).await?.collect().await;
+        let groups: HashMap<String, Group> = groups.into_iter()
+            .flatten()
+            .map(|group| (group.id.as_ref().unwrap().to_hex(), group))
+            .collect();
+        Ok(users.iter().map(|user| {
+            let responses = user.groups.iter().flatten()
+                .filter_map(|id| groups.get(id))
+                .map(|group| group.to_owned().to_response())
+                .collect();
+            user.response_with(responses)
+        }).collect())
+    }
+    fn response_with(&self, groups: Vec<serde_json::Value>) -> serde_json::Value {
         json!(UserResponse {
             id: self.id.clone().unwrap().to_string(),
             name: self.name.clone(),
@@ -60,8 +127,104 @@ impl User {
             created_at: self.created_at.timestamp(),
             groups: groups,
             avatar: self.avatar.clone(),
+            avatar_sizes: self.avatar_sizes.clone(),
+            two_factor: self.totp_enabled,
+            role: self.role_name().to_owned(),
         })
     }
+    pub fn role_name(&self) -> &str {
+        self.role.as_deref().unwrap_or_else(|| crate::roles::role_for_level(self.permission as i8))
+    }
+    // 校验两步验证码 也可以使用一个恢复码 恢复码使用后即作废
+    pub async fn verify_second_factor(&mut self, db: &Database, code: &str) -> wither::Result<bool> {
+        if let Some(secret) = &self.totp_secret {
+            if let Some(step) = crate::totp::verify(secret, code, self.totp_last_step) {
+                // 并发的请求可能已经使用了这个周期 只有更新成功才算通过
+                let result = User::collection(db).update_one(doc! {
+                    "_id": self.id.as_ref().unwrap(),
+                    "totp_last_step": {"$not": {"$gte": step}},
+                }, doc! {
+                    "$set": {"totp_last_step": step}
+                }, None).await?;
+                self.totp_last_step = Some(step);
+                return Ok(result.modified_count == 1);
+            }
+        }
+        let hash = crate::session::hash_token(code.trim());
+        if let Some(codes) = self.recovery_codes.as_mut() {
+            if let Some(index) = codes.iter().position(|c| *c == hash) {
+                // 与验证码相同 只有成功移除恢复码的请求才算通过
+                let result = User::collection(db).update_one(doc! {
+                    "_id": self.id.as_ref().unwrap(),
+                    "recovery_codes": &hash,
+                }, doc! {
+                    "$pull": {"recovery_codes": &hash}
+                }, None).await?;
+                codes.remove(index);
+                return Ok(result.modified_count == 1);
+            }
+        }
+        Ok(false)
+    }
+    // 标记为正在删除 并立即注销这个用户
+    // 之后的每一步都是幂等的 中断后调用 finish_deletion 即可继续
+    pub async fn begin_deletion(&mut self, db: &Database, plan: DeletionPlan) -> wither::Result<()> {
+        self.deleting = Some(plan);
+        self.save(db, None).await?;
+        let uid = self.id.as_ref().unwrap().to_hex();
+        for collection in ["sessions", "api_tokens"] {
+            db.collection(collection).delete_many(doc! {"user": &uid}, None).await?;
+        }
+        Ok(())
+    }
+    pub async fn finish_deletion(self, db: &Database) -> wither::Result<()> {
+        let uid = self.id.as_ref().unwrap().to_hex();
+        let owner = match &self.deleting {
+            Some(plan) => plan.new_owner().to_owned(),
+            None => return Ok(()),
+        };
+        // 清理引用
+        db.collection("groups").update_many(doc! {"managers": &uid}, doc! {
+            "$pull": {"managers": &uid}
+        }, None).await?;
+        db.collection("records").update_many(doc! {"collaborators": &uid}, doc! {
+            "$pull": {"collaborators": &uid}
+        }, None).await?;
+        // 转移或匿名化数据
+        let ownership = [
+            ("records", "user"),
+            ("storage", "owner"),
+            ("detections", "creator"),
+            ("video_detections", "creator"),
+        ];
+        for (collection, field) in ownership {
+            db.collection(collection).update_many(doc! {field: &uid}, doc! {
+                "$set": {field: &owner}
+            }, None).await?;
+        }
+        // 只属于这个用户的数据直接删除
+        for collection in ["drafts", "sessions", "api_tokens", "password_resets", "email_changes", "memberships"] {
+            db.collection(collection).delete_many(doc! {"user": &uid}, None).await?;
+        }
+        crate::models::export_jobs::ExportJob::clear_user(db, &uid).await?;
+        db.collection("login_attempts").delete_many(doc! {
+            "key": crate::models::login_attempts::LoginAttempt::user_key(&uid)
+        }, None).await?;
+        self.delete(db).await?;
+        Ok(())
+    }
+    // 启动时继续上次被中断的删除
+    pub async fn resume_deletions(db: &Database) -> wither::Result<usize> {
+        let users: Vec<_> = User::find(db, Some(doc! {
+            "deleting": {"$exists": true}
+        }), None).await?.collect().await;
+        let mut resumed = 0;
+        for user in users.into_iter().flatten() {
+            user.finish_deletion(db).await?;
+            resumed += 1;
+        }
+        Ok(resumed)
+    }
     pub async fn by_group(db: &Database, group: &String) -> Vec<Self> {
         let filter = doc! {"groups": {"$elemMatch": {
             "$eq": group
@@ -83,6 +246,13 @@ impl User {
 
 impl SearchById for User {}
 
+// 某个尺寸的正方形头像 id为Storage的id
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AvatarSize {
+    pub size: i32,
+    pub id: String,
+}
+
 // 用于返回给前端的用户信息
 #[derive(Debug, Serialize, Clone)]
 pub struct UserResponse {
@@ -95,4 +265,9 @@ pub struct UserResponse {
     pub groups: Vec<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub avatar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_sizes: Option<Vec<AvatarSize>>,
+    // 是否启用了两步验证
+    pub two_factor: bool,
+    pub role: String,
 }
\ No newline at end of file
@@ -0,0 +1,76 @@
+use wither::bson::DateTime;
+use wither::bson::oid::ObjectId;
+use swift_det_lib::DetectConfig;
+use crate::models::detections::make_config;
+use crate::models::storage::Storage;
+use wither::Model;
+use serde::{Serialize, Deserialize};
+use wither::mongodb::Database;
+use crate::models::SearchById;
+
+// 视频中某一帧的检测结果
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FrameCount {
+    // 帧在视频中的时间 单位为秒
+    pub time: f64,
+    // 超过阈值的检测框数量
+    pub num: i32,
+}
+
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(collection_name = "video_detections")]
+pub struct VideoDetection {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub creator: String,
+    pub created_at: DateTime,
+    pub status: String,
+    pub attachment: String,
+    pub window_size: isize,
+    pub overlap: i8,
+    pub tile_max_num: i16,
+    pub model_name: String,
+    // 每秒抽取的帧数
+    pub sample_rate: f64,
+    // 计数时使用的阈值
+    pub threshold: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frames: Option<Vec<FrameCount>>,
+    // 数量最多的一帧
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak: Option<FrameCount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current: Option<isize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<isize>,
+}
+
+impl VideoDetection {
+    pub fn get_config(&self, model_path: String) -> DetectConfig {
+        make_config(self.window_size, self.overlap, self.tile_max_num, model_path)
+    }
+    pub async fn get_attachment(&self, db: &Database) -> Option<Storage> {
+        Storage::by_id(db, &self.attachment).await
+    }
+    pub fn to_response(self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id.unwrap().to_hex(),
+            "creator": self.creator,
+            "created_at": self.created_at.timestamp(),
+            "status": self.status,
+            "attachment": self.attachment,
+            "window_size": self.window_size,
+            "overlap": self.overlap,
+            "tile_max_num": self.tile_max_num,
+            "model_name": self.model_name,
+            "sample_rate": self.sample_rate,
+            "threshold": self.threshold,
+            "frames": self.frames,
+            "peak": self.peak,
+            "current": self.current,
+            "total": self.total,
+        })
+    }
+}
+
+impl SearchById for VideoDetection {}
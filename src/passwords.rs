@@ -0,0 +1,47 @@
+// 服务端密码哈希
+// 客户端提交的是加盐后的MD5 服务端再用Argon2id保存
+// 旧版本直接保存了客户端的MD5 在下次登录成功时迁移
+
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use std::sync::OnceLock;
+
+const ARGON2_PREFIX: &str = "$argon2";
+
+// 客户端提交密码前的加盐MD5 与 /system/encrypt 一致
+pub fn client_digest(content: &str) -> String {
+    let content = format!("ここで振り返る{}もうすぐだよ{}知らない世界も{}歩いてみよう", content, content, content);
+    format!("{:x}", md5::compute(content.as_bytes()))
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("无法计算密码哈希")
+        .to_string()
+}
+
+// 是否为旧版直接保存的MD5
+pub fn is_legacy(stored: &str) -> bool {
+    !stored.starts_with(ARGON2_PREFIX)
+}
+
+pub fn verify_password(stored: &str, password: &str) -> bool {
+    if is_legacy(stored) {
+        return stored == password;
+    }
+    if let Ok(hash) = PasswordHash::new(stored) {
+        Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+    } else {
+        false
+    }
+}
+
+// 对一个固定的哈希做一次校验 用于在用户不存在时消耗相同的时间
+pub fn dummy_verify(password: &str) {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let stored = DUMMY.get_or_init(|| hash_password("dummy"));
+    verify_password(stored, password);
+}
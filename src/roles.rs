@@ -0,0 +1,68 @@
+// 角色与能力
+// 每个角色拥有一组能力 接口按照能力而不是数字权限检查
+// 角色与能力的对应关系可以在配置文件的 [roles] 中修改
+
+use std::collections::HashMap;
+use log::info;
+use wither::bson::doc;
+use wither::mongodb::Database;
+
+// 管理自己的账号 会话 令牌等
+pub const ACCOUNT: &str = "account";
+pub const RECORDS_READ: &str = "records:read";
+pub const RECORDS_WRITE: &str = "records:write";
+// 修改和删除任何人的记录
+pub const RECORDS_ADMIN: &str = "records:admin";
+pub const DETECTOR_USE: &str = "detector:use";
+pub const STORAGE_UPLOAD: &str = "storage:upload";
+// 创建小组 修改小组和填报点还需要是对应小组的管理员
+pub const GROUPS_MANAGE: &str = "groups:manage";
+// 管理所有小组 不要求是小组的管理员
+pub const GROUPS_ADMIN: &str = "groups:admin";
+pub const PROJECTS_ADMIN: &str = "projects:admin";
+pub const USERS_ADMIN: &str = "users:admin";
+pub const SYSTEM_ADMIN: &str = "system:admin";
+
+// 内置的角色 与旧版的数字权限一一对应
+pub const GUEST: &str = "guest";
+pub const MEMBER: &str = "member";
+pub const MANAGER: &str = "manager";
+pub const ADMIN: &str = "admin";
+
+pub fn role_for_level(level: i8) -> &'static str {
+    match level {
+        1 => MEMBER,
+        2 => MANAGER,
+        3 => ADMIN,
+        _ => GUEST,
+    }
+}
+
+pub fn default_roles() -> HashMap<String, Vec<String>> {
+    let guest = vec![STORAGE_UPLOAD];
+    let member = [guest.clone(), vec![ACCOUNT, RECORDS_READ, RECORDS_WRITE, DETECTOR_USE]].concat();
+    let manager = [member.clone(), vec![GROUPS_MANAGE]].concat();
+    let admin = [manager.clone(), vec![GROUPS_ADMIN, RECORDS_ADMIN, PROJECTS_ADMIN, USERS_ADMIN, SYSTEM_ADMIN]].concat();
+    [(GUEST, guest), (MEMBER, member), (MANAGER, manager), (ADMIN, admin)]
+        .into_iter()
+        .map(|(role, capabilities)| (role.to_string(), capabilities.into_iter().map(String::from).collect()))
+        .collect()
+}
+
+// 为还没有角色的用户按照数字权限分配角色
+pub async fn migrate_users(db: &Database) -> wither::Result<i64> {
+    let mut migrated = 0;
+    for level in 0..=3 {
+        let result = db.collection("users").update_many(doc! {
+            "role": {"$exists": false},
+            "permission": level as f64,
+        }, doc! {
+            "$set": {"role": role_for_level(level)}
+        }, None).await?;
+        migrated += result.modified_count;
+    }
+    if migrated > 0 {
+        info!("为 {} 个用户分配了角色", migrated);
+    }
+    Ok(migrated)
+}
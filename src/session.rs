@@ -1,103 +1,228 @@
-use rand::Rng;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
 use tide::{Middleware, Next, Request};
 use crate::AppState;
-use crate::models::Session;
+use crate::config::SessionConfig;
+use crate::models::{SearchById, Session};
+use crate::models::api_tokens::{ApiToken, SCOPE_DETECTOR_USE, SCOPE_RECORDS_READ, SCOPE_RECORDS_WRITE};
+use crate::models::users::User;
+use crate::apis::json_response;
 use log::{error, info, warn};
 use tide::http::Cookie;
+use tide::http::cookies::SameSite;
+use tide::http::Method;
+use tide::prelude::json;
 use wither::bson::doc;
 use wither::Model;
 
 
 pub(crate) struct SessionMiddleware {}
 
-fn generate_fingerprint() -> String {
-    // 生成一个随机的字符串
-    let mut rng = rand::thread_rng();
-    let mut s = String::new();
-    for _ in 0..32 {
-        s.push(rng.gen_range(b'a'..b'z') as char);
-    }
-    s
+// 生成一个随机的令牌 只保存在客户端的cookie中
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// 数据库中只保存令牌的哈希
+pub(crate) fn hash_token(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn make_cookie(config: &SessionConfig, token: String) -> Cookie<'static> {
+    let same_site = match config.cookie_same_site.to_lowercase().as_str() {
+        "strict" => SameSite::Strict,
+        "none" => SameSite::None,
+        _ => SameSite::Lax,
+    };
+    Cookie::build("fingerprint", token)
+        .path("/")
+        .http_only(config.cookie_http_only)
+        .secure(config.cookie_secure)
+        .same_site(same_site)
+        .finish()
 }
 
-fn default_session(timeout: u64, ip: String) -> Session {
+// 匿名的session 只存在于内存中
+// 直到有处理函数写入(例如登录)时才会被保存并下发cookie
+fn default_session(timeout: u64, ip: String, user_agent: Option<String>) -> Session {
     let utc_now = chrono::prelude::Utc::now();
     let expire_at = utc_now + chrono::Duration::seconds(timeout as i64);
     Session {
         id: None,
-        fingerprint: generate_fingerprint(),
+        fingerprint: String::new(),
         login: false,
         permission: 0,
         user: None,
         expire_at: expire_at.into(),
         ip,
+        user_agent,
+        created_at: Some(utc_now.into()),
+        last_seen: Some(utc_now.into()),
+        role: None,
+        token: None,
+        scopes: None,
+    }
+}
+
+// 访问某个路径所需的令牌权限范围 满足其一即可
+// 返回None的路径不允许使用令牌访问
+fn required_scope(path: &str, method: Method) -> Option<&'static [&'static str]> {
+    let read = method == Method::Get;
+    if path.starts_with("/detector") {
+        return Some(&[SCOPE_DETECTOR_USE]);
     }
+    if path.starts_with("/records") || path.starts_with("/drafts") {
+        return if read { Some(&[SCOPE_RECORDS_READ]) } else { Some(&[SCOPE_RECORDS_WRITE]) };
+    }
+    if path.starts_with("/storage") {
+        // 上传的附件既可以用于记录 也可以用于检测
+        return if read {
+            Some(&[SCOPE_RECORDS_READ, SCOPE_DETECTOR_USE])
+        } else {
+            Some(&[SCOPE_RECORDS_WRITE, SCOPE_DETECTOR_USE])
+        };
+    }
+    if read && ["/positions", "/projects", "/groups"].iter().any(|p| path.starts_with(p)) {
+        return Some(&[SCOPE_RECORDS_READ]);
+    }
+    None
+}
+
+fn token_rejected(status: u16, cn: &str, en: &str) -> tide::Response {
+    json_response(status, json!({
+        "code": 1,
+        "message": {
+            "cn": cn,
+            "en": en,
+        },
+    }))
+}
+
+// 使用 Authorization: Bearer 认证的请求
+// 不读取也不下发cookie 只在内存中构造session
+async fn handle_bearer(mut request: Request<AppState>, next: Next<'_, AppState>, raw: String) -> tide::Result {
+    let state = request.state().clone();
+    let token = match ApiToken::by_raw(&state.db, &raw).await {
+        Some(token) => token,
+        None => return Ok(token_rejected(401, "令牌无效或已过期", "Invalid or expired token")),
+    };
+    let user = match User::by_id(&state.db, &token.user).await {
+        Some(user) => user,
+        None => return Ok(token_rejected(401, "令牌无效或已过期", "Invalid or expired token")),
+    };
+    let allowed = required_scope(request.url().path(), request.method());
+    if !allowed.is_some_and(|scopes| scopes.iter().any(|s| token.has_scope(s))) {
+        return Ok(token_rejected(403, "令牌没有访问此终端的权限", "Token scope does not allow this endpoint"));
+    }
+    token.touch(&state.db).await;
+    let now = chrono::Utc::now();
+    let mut session = Session {
+        id: None,
+        fingerprint: String::new(),
+        login: false,
+        permission: 0,
+        user: None,
+        expire_at: token.expire_at.unwrap_or_else(|| (now + chrono::Duration::seconds(state.config.session.timeout as i64)).into()),
+        ip: request.remote().unwrap_or("-").to_string(),
+        user_agent: request.header("User-Agent").map(|h| h.as_str().to_owned()),
+        created_at: None,
+        last_seen: None,
+        role: None,
+        token: None,
+        scopes: Some(token.scopes),
+    };
+    // 角色仍然来自账号 has_capability 会再按令牌的权限范围限制
+    session.grant(&user, &state.config);
+    request.set_ext(session);
+    Ok(next.run(request).await)
 }
 
 #[async_trait::async_trait]
 impl Middleware<AppState> for SessionMiddleware {
     async fn handle(&self, mut request: Request<AppState>, next: Next<'_, AppState>) -> tide::Result {
-        let state = request.state();
+        let bearer = request.header("Authorization")
+            .and_then(|h| h.as_str().strip_prefix("Bearer "))
+            .map(|raw| raw.trim().to_owned());
+        if let Some(raw) = bearer {
+            return handle_bearer(request, next, raw).await;
+        }
+        let state = request.state().clone();
+        let mut session = None;
         if let Some(fingerprint) = request.cookie("fingerprint") {
             // 如果 cookie 中有 fingerprint，尝试从数据库中查找
-            if let Some(mut session) = Session::find_by_fingerprint(&state.db, fingerprint.value()).await {
-                if session.ip != request.remote().unwrap_or("-").to_string() {
+            let fingerprint = hash_token(fingerprint.value());
+            if let Some(mut found) = Session::find_by_fingerprint(&state.db, &fingerprint).await {
+                let mut valid = true;
+                if found.ip != request.remote().unwrap_or("-").to_string() {
                     // ip发生了变化
                     if state.config.session.logout_on_ip_change {
                         // 删除这个 session
-                        warn!("IP发生了变化 删除session {}", session.fingerprint);
-                        if let Err(e) = session.delete(&state.db).await {
+                        warn!("IP发生了变化 删除session {}", found.fingerprint);
+                        if let Err(e) = found.delete(&state.db).await {
                             error!("无法删除 session: {}", e);
                             return Err(tide::Error::from(e));
                         }
+                        valid = false;
                     } else {
                         // 更新 ip
-                        let _session = session.update_ip(&state.db, request.remote().unwrap_or("-")).await;
+                        let _session = found.update_ip(&state.db, request.remote().unwrap_or("-")).await;
                         if let Err(e) = _session {
                             error!("无法更新 session ip: {:?}", e);
                             return Err(tide::Error::from(e));
                         }
-                        session = _session.unwrap();
-                        info!("更新 session ip {}", session.ip);
+                        found = _session.unwrap();
+                        info!("更新 session ip {}", found.ip);
                     }
                 }
-                // 为这个 session 刷新过期时间
-                if let Ok(session) = session.update_timeout(&state.db, state.config.session.timeout).await {
-                    // 将 session 放入请求中
-                    request.set_ext(session);
-                    // 获得响应
-                    return Ok(next.run(request).await);
-                } else {
-                    error!("无法更新 session 过期时间, 将删除这个 session");
+                if valid {
+                    // 为这个 session 刷新过期时间
+                    if let Ok(found) = found.update_timeout(&state.db, state.config.session.timeout).await {
+                        session = Some(found);
+                    } else {
+                        error!("无法更新 session 过期时间, 将删除这个 session");
 
-                    if let Err(e) = Session::find_one_and_delete(&state.db, doc! {
-                        "fingerprint": fingerprint.value()
-                    }, None).await {
-                        error!("无法删除 session: {}", e);
-                        return Err(tide::Error::from(e));
+                        if let Err(e) = Session::find_one_and_delete(&state.db, doc! {
+                            "fingerprint": &fingerprint
+                        }, None).await {
+                            error!("无法删除 session: {}", e);
+                            return Err(tide::Error::from(e));
+                        }
                     }
                 }
             }
         }
-        // 客户端没有 cookie，则生成一个新的 fingerprint
-        let ip = request.remote().unwrap_or("-");
-        let mut session = default_session(state.config.session.timeout, ip.to_string());
-        if let Err(e) = session.save(&state.db, None).await {
-            error!("无法保存 session: {}", e);
-            return Err(tide::Error::from(e));
-        }
-        info!("创建了新的session: {}", session.fingerprint);
+        let session = match session {
+            Some(session) => session,
+            None => {
+                // 客户端没有有效的 cookie，则使用一个临时的 session
+                let ip = request.remote().unwrap_or("-");
+                let user_agent = request.header("User-Agent").map(|h| h.as_str().to_owned());
+                default_session(state.config.session.timeout, ip.to_string(), user_agent)
+            }
+        };
+        let mut token = session.token.clone();
         // 将 session 放入请求中
-        let fingerprint = session.fingerprint.clone();
         request.set_ext(session);
         // 获得响应
         let mut resp = next.run(request).await;
-        // 将新的 fingerprint 放入 cookie
-        resp.insert_cookie(Cookie::new("fingerprint", fingerprint));
+        // 处理函数轮换了令牌时 会将 session 放入响应中
+        if let Some(rotated) = resp.ext::<Session>() {
+            if rotated.token.is_some() {
+                token = rotated.token.clone();
+            }
+        }
+        // 将新的令牌放入 cookie
+        if let Some(token) = token {
+            resp.insert_cookie(make_cookie(&state.config.session, token));
+        }
         Ok(resp)
     }
 
     fn name(&self) -> &str {
         "SessionMiddleware"
     }
-}
\ No newline at end of file
+}
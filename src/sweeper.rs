@@ -0,0 +1,122 @@
+// 定期清理过期的检测任务
+// 包括任务本身 调试用的Heatmap 以及不再被引用的附件
+// 同时清理过期的数据导出文件
+
+use std::collections::HashSet;
+use std::time::Duration;
+use futures::StreamExt;
+use log::{error, info, warn};
+use wither::bson::{doc, Document};
+use wither::Model;
+use wither::mongodb::Database;
+use crate::config::RetentionConfig;
+use crate::models::detections::Detection;
+use crate::models::export_jobs::{self, ExportJob};
+use crate::models::SearchById;
+use crate::models::storage::Storage;
+use crate::models::video_detections::VideoDetection;
+
+pub async fn run(db: Database, config: RetentionConfig) {
+    info!("启动任务清理器 间隔 {} 秒", config.interval);
+    loop {
+        sweep(&db, &config).await;
+        async_std::task::sleep(Duration::from_secs(config.interval)).await;
+    }
+}
+
+fn older_than(mut filter: Document, days: i64) -> Document {
+    let deadline = chrono::Utc::now() - chrono::Duration::days(days);
+    filter.insert("created_at", doc! {"$lt": deadline});
+    filter
+}
+
+async fn sweep(db: &Database, config: &RetentionConfig) {
+    let rules = [
+        // 匿名任务无论状态如何都会被清理
+        older_than(doc! {"creator": "anonymous"}, config.anonymous_days),
+        older_than(doc! {"status": "failed"}, config.failed_days),
+        older_than(doc! {"status": "finished"}, config.finished_days),
+    ];
+    let mut attachments = HashSet::new();
+    let mut removed = 0;
+    for filter in rules {
+        removed += sweep_detections(db, filter.clone(), &mut attachments).await;
+        removed += sweep_video_detections(db, filter, &mut attachments).await;
+    }
+    // 任务删除完毕后 再清理没有被引用的附件
+    let mut orphans = 0;
+    for attachment in attachments {
+        if Storage::in_use(db, &attachment).await {
+            continue;
+        }
+        if let Some(storage) = Storage::by_id(db, &attachment).await {
+            if let Err(e) = storage.remove(db).await {
+                warn!("无法删除附件 {}: {}", &attachment, e);
+            } else {
+                orphans += 1;
+            }
+        }
+    }
+    if removed > 0 || orphans > 0 {
+        info!("清理了 {} 个过期任务 {} 个附件", removed, orphans);
+    }
+    sweep_exports(db).await;
+}
+
+async fn sweep_exports(db: &Database) {
+    let deadline = chrono::Utc::now() - chrono::Duration::minutes(export_jobs::TIMEOUT_MINUTES);
+    match ExportJob::fail_stale(db, deadline).await {
+        Ok(0) => {}
+        Ok(failed) => warn!("{} 个导出任务超时", failed),
+        Err(e) => error!("无法检查超时的导出任务: {}", e),
+    }
+    match ExportJob::expire(db).await {
+        Ok(0) => {}
+        Ok(expired) => info!("清理了 {} 个过期的导出文件", expired),
+        Err(e) => error!("无法清理过期的导出文件: {}", e),
+    }
+}
+
+async fn sweep_detections(db: &Database, filter: Document, attachments: &mut HashSet<String>) -> usize {
+    let tasks: Vec<_> = match Detection::find(db, Some(filter), None).await {
+        Ok(cursor) => cursor.collect().await,
+        Err(e) => {
+            error!("无法查询过期任务: {}", e);
+            return 0;
+        }
+    };
+    let mut removed = 0;
+    for task in tasks.into_iter().flatten() {
+        if let Err(e) = task.remove_heatmap(db).await {
+            warn!("无法删除任务 {} 的Heatmap: {}", task.id.as_ref().unwrap(), e);
+            continue;
+        }
+        if let Err(e) = task.delete(db).await {
+            warn!("无法删除任务 {}: {}", task.id.as_ref().unwrap(), e);
+            continue;
+        }
+        attachments.insert(task.attachment);
+        removed += 1;
+    }
+    removed
+}
+
+async fn sweep_video_detections(db: &Database, filter: Document, attachments: &mut HashSet<String>) -> usize {
+    let tasks: Vec<_> = match VideoDetection::find(db, Some(filter), None).await {
+        Ok(cursor) => cursor.collect().await,
+        Err(e) => {
+            error!("无法查询过期视频任务: {}", e);
+            return 0;
+        }
+    };
+    let mut removed = 0;
+    for task in tasks.into_iter().flatten() {
+        if let Err(e) = task.delete(db).await {
+            warn!("无法删除视频任务 {}: {}", task.id.as_ref().unwrap(), e);
+            continue;
+        }
+        attachments.insert(task.attachment);
+        removed += 1;
+    }
+    removed
+}
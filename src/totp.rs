@@ -0,0 +1,164 @@
+// 基于时间的一次性密码 (RFC 6238)
+// 使用HMAC-SHA1 30秒一个周期 6位数字 与常见的验证器应用兼容
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha1::Sha1;
+
+const PERIOD: i64 = 30;
+const DIGITS: u32 = 6;
+// 允许前后各一个周期的时钟误差
+const SKEW: i64 = 1;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// 不带填充的Base32编码 验证器应用使用这种格式的密钥
+fn base32_encode(data: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    result
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in data.trim_end_matches('=').chars() {
+        let c = c.to_ascii_uppercase() as u8;
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Some(result)
+}
+
+// 生成一个新的160位密钥
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    base32_encode(&bytes)
+}
+
+fn code_at(key: &[u8], counter: u64, digits: u32) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC可以接受任意长度的密钥");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+    format!("{:0width$}", binary % 10u32.pow(digits), width = digits as usize)
+}
+
+// 校验用户输入的验证码 成功时返回对应的周期序号
+// 不接受不晚于last_step的周期 防止同一个验证码被重复使用
+pub fn verify(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    verify_at(secret, code, chrono::Utc::now().timestamp(), last_step)
+}
+
+fn verify_at(secret: &str, code: &str, time: i64, last_step: Option<i64>) -> Option<i64> {
+    let key = base32_decode(secret)?;
+    let code = code.trim();
+    let counter = time / PERIOD;
+    (-SKEW..=SKEW).map(|offset| counter + offset).find(|counter| {
+        *counter >= 0
+            && last_step.is_none_or(|last| *counter > last)
+            && code_at(&key, *counter as u64, DIGITS) == code
+    })
+}
+
+// 供验证器应用扫描的URI
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            urlencoding::encode(issuer),
+            urlencoding::encode(account),
+            secret,
+            urlencoding::encode(issuer),
+            DIGITS,
+            PERIOD)
+}
+
+// 丢失验证器时使用的一次性恢复码
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    (0..count).map(|_| {
+        let mut bytes = [0u8; 5];
+        OsRng.fill_bytes(&mut bytes);
+        let code = base32_encode(&bytes).to_lowercase();
+        format!("{}-{}", &code[..4], &code[4..])
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 附录B中SHA1的测试密钥
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+    const RFC_VECTORS: [(i64, &str); 6] = [
+        (59, "94287082"),
+        (1111111109, "07081804"),
+        (1111111111, "14050471"),
+        (1234567890, "89005924"),
+        (2000000000, "69279037"),
+        (20000000000, "65353130"),
+    ];
+
+    #[test]
+    fn rfc6238_vectors() {
+        for (time, expected) in RFC_VECTORS {
+            assert_eq!(code_at(RFC_KEY, (time / PERIOD) as u64, 8), expected, "T = {}", time);
+        }
+    }
+
+    #[test]
+    fn verify_six_digit_codes() {
+        let secret = base32_encode(RFC_KEY);
+        for (time, expected) in RFC_VECTORS {
+            // 6位验证码是8位结果的后6位
+            let code = &expected[2..];
+            assert_eq!(verify_at(&secret, code, time, None), Some(time / PERIOD));
+        }
+        assert_eq!(verify_at(&secret, "000000", 59, None), None);
+    }
+
+    #[test]
+    fn verify_allows_clock_skew() {
+        let secret = base32_encode(RFC_KEY);
+        assert_eq!(verify_at(&secret, "287082", 59 + PERIOD, None), Some(1));
+        assert_eq!(verify_at(&secret, "287082", 59 + 2 * PERIOD, None), None);
+    }
+
+    #[test]
+    fn verify_rejects_replay() {
+        let secret = base32_encode(RFC_KEY);
+        let step = verify_at(&secret, "081804", 1111111109, None).unwrap();
+        assert_eq!(verify_at(&secret, "081804", 1111111109, Some(step)), None);
+        // 之后周期的验证码仍然可以使用
+        assert_eq!(verify_at(&secret, "050471", 1111111111, Some(step)), Some(step + 1));
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_decode(&secret).map(|key| base32_encode(&key)), Some(secret));
+        assert_eq!(base32_encode(RFC_KEY), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+}